@@ -1,51 +1,33 @@
-use anyhow::{bail, Result};
-use core::str;
-use embedded_svc::{http::client::Client, io::Read};
+use anyhow::Result;
 use esp_idf_hal::{gpio::*, prelude::Peripherals, reset};
-use esp_idf_svc::{
-    eventloop::EspSystemEventLoop,
-    http::client::{Configuration, EspHttpConnection},
-    nvs::EspDefaultNvsPartition,
-};
+use esp_idf_svc::{eventloop::EspSystemEventLoop, nvs::EspDefaultNvsPartition, sntp::EspSntp};
 use subscription::subscribe_pin;
 
-use log::info;
-use std::{thread, time::Duration};
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+};
 
 mod wifi;
 use wifi::wifi;
 // If using the `binstart` feature of `esp-idf-sys`, always keep this module imported
 use esp_idf_sys::{self as _, esp, nvs_flash_erase};
 
-use serde::{Deserialize, Serialize};
-
-use semver::Version;
-
+use crate::ota::{verify_image, OtaConfig, OtaStatus, SelfTest};
 use crate::run::run;
+use crate::storage::Storage;
+
+mod ota;
 
 mod run;
 
+mod storage;
+
 mod subscription;
 
-#[derive(Serialize, Deserialize, Debug)]
-struct UpdateJson {
-    version: String,
-    link: String,
-}
-
-#[derive(Debug)]
-struct Update {
-    version: Version,
-    link: String,
-}
-
-impl Update {
-    pub fn new(json: UpdateJson) -> Update {
-        let version = Version::parse(&json.version).unwrap();
-        let link = json.link;
-        Update { version, link }
-    }
-}
+/// JSON, NVS writes and logging all happen on the run thread, the default
+/// pthread stack is too small for them.
+const RUN_STACK_SIZE: usize = 16 * 1024;
 
 fn reset_request() {
     let _ = esp!(unsafe { nvs_flash_erase() });
@@ -63,113 +45,45 @@ fn main() -> Result<()> {
     let (pin0, pins) = Pines::new(peripherals.pins);
     let _reset = subscribe_pin(pin0, reset_request);
 
-    let _wifi = wifi(peripherals.modem, sysloop, nvs_partition)?;
-
-    let run_thread = thread::spawn(move || run(pins));
-
-    let link = ota()?;
-
-    ota_update(link)?;
+    let storage = Storage::new(nvs_partition.clone())?;
+
+    let ota_config = Arc::new(Mutex::new(OtaConfig::load(&storage)));
+    let ota_status = Arc::new(Mutex::new(OtaStatus::new(&storage)));
+    let self_test = Arc::new(SelfTest::default());
+    verify_image(self_test.clone(), storage.clone())?;
+
+    let connected = self_test.clone();
+    let (wifi_networks, wifi_status) = wifi(
+        peripherals.modem,
+        sysloop,
+        nvs_partition,
+        storage.clone(),
+        move || connected.wifi_up(),
+    )?;
+
+    // Keeps the clock right for the OTA maintenance window
+    let _sntp = EspSntp::new_default()?;
+
+    let run_thread = thread::Builder::new()
+        .name("run".to_owned())
+        .stack_size(RUN_STACK_SIZE)
+        .spawn(move || {
+            run(
+                pins,
+                storage,
+                ota_config,
+                ota_status,
+                self_test,
+                wifi_networks,
+                wifi_status,
+            )
+        })?;
 
     let _ = run_thread.join();
 
     Ok(())
 }
 
-fn ota() -> Result<String> {
-    let json_link =
-        "https://raw.githubusercontent.com/Mirkopoj/caudalimeto_bomba/master/update.json";
-
-    let update = loop {
-        if let Ok(update) = check_update(json_link) {
-            break update;
-        }
-    };
-
-    let version = update.version;
-
-    loop {
-        thread::sleep(Duration::from_secs(3600));
-        if let Ok(update) = check_update(json_link) {
-            println!("Version actual: {}", version);
-            println!("Version leida: {}", update.version);
-            if update.version > version {
-                break;
-            }
-        }
-    }
-
-    Ok(update.link)
-}
-
-fn connect() -> Result<Client<EspHttpConnection>> {
-    let connection = EspHttpConnection::new(&Configuration {
-        use_global_ca_store: true,
-        crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
-        ..Default::default()
-    })?;
-    let client = Client::wrap(connection);
-
-    Ok(client)
-}
-
-fn check_update(url: impl AsRef<str>) -> Result<Update> {
-    let mut client = connect()?;
-    let request = client.get(url.as_ref())?;
-    let response = request.submit()?;
-    let status = response.status();
-
-    let update: Update;
-
-    match status {
-        200..=299 => {
-            let mut buf = [0_u8; 256];
-            let mut reader = response;
-            let size = Read::read(&mut reader, &mut buf)?;
-            if size == 0 {
-                bail!("Zero sized message");
-            }
-            update = Update::new(serde_json::from_slice(&buf[..size])?);
-        }
-        _ => bail!("Unexpected response code: {}", status),
-    }
-
-    Ok(update)
-}
-
-fn ota_update(url: impl AsRef<str>) -> Result<()> {
-    let mut client = connect()?;
-    let request = client.get(url.as_ref())?;
-    let response = request.submit()?;
-    let status = response.status();
-    let mut ota = esp_ota::OtaUpdate::begin()?;
-
-    info!("Begin OTA");
-
-    match status {
-        200..=299 => {
-            let mut buf = [0_u8; 256];
-            let mut reader = response;
-            loop {
-                let size = Read::read(&mut reader, &mut buf)?;
-                info!("Read {} bytes", size);
-                if size == 0 {
-                    break;
-                }
-                ota.write(&buf)?;
-                info!("Wrote {} bytes", size);
-            }
-        }
-
-        _ => bail!("Unexpected response code: {}", status),
-    }
-
-    let mut completed_ota = ota.finalize()?;
-    completed_ota.set_as_boot_partition()?;
-    info!("OTA Complete");
-    completed_ota.restart();
-}
-
 pub struct Pines {
     #[cfg(not(feature = "riscv-ulp-hal"))]
     pub gpio1: Gpio1,
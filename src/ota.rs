@@ -0,0 +1,980 @@
+use anyhow::{anyhow, bail, Result};
+use core::str;
+use ed25519_dalek::{Signature, VerifyingKey};
+use embedded_svc::{
+    http::{client::Client, Headers},
+    io::Read,
+};
+use esp_idf_hal::reset;
+use esp_idf_svc::{
+    errors::EspIOError,
+    http::client::{Configuration, EspHttpConnection},
+};
+use esp_idf_sys::{
+    esp, esp_ota_get_next_update_partition, esp_ota_get_running_partition,
+    esp_ota_get_state_partition, esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY,
+    esp_ota_mark_app_invalid_rollback_and_reboot, esp_ota_mark_app_valid_cancel_rollback,
+    esp_ota_set_boot_partition, esp_timer_get_time,
+};
+use log::{info, warn};
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::storage::Storage;
+use std::{
+    ptr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Ed25519 key that signs the update manifests, see `tools/ota_sign`.
+const OTA_PUBLIC_KEY: &str = include_str!("../ota_key.pub");
+const MAX_MANIFEST_LEN: usize = 2048;
+/// A read that blocks longer than this counts as a stalled download.
+/// Uploads use the HTTP server receive timeout instead.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// Upper bound for the whole image download.
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+/// A freshly updated image rolls back unless it passes the self-test in time.
+const SELF_TEST_WINDOW: Duration = Duration::from_secs(3 * 60);
+const ROLLBACK_KEY: &str = "ota.rollback";
+const CONFIG_KEY: &str = "ota";
+const DEFAULT_MANIFEST_URL: &str =
+    "https://raw.githubusercontent.com/Mirkopoj/caudalimetro_bomba/master/update.json";
+const DEFAULT_CHECK_INTERVAL: u64 = 3600;
+const MIN_CHECK_INTERVAL: u64 = 60;
+/// First retry after a failed check, doubled on every consecutive failure
+/// up to the check interval.
+const RETRY_BACKOFF: u64 = 30;
+const TASK_STACK_SIZE: usize = 16 * 1024;
+/// Only waits for the pump and the window, then boots the uploaded image.
+const APPLY_STACK_SIZE: usize = 6 * 1024;
+/// Records the outcome of the self-test in NVS and logs.
+const VERIFY_STACK_SIZE: usize = 6 * 1024;
+/// Lets the answer to an upload leave before a restart.
+const APPLY_DELAY: Duration = Duration::from_secs(2);
+const TOKEN_KEY: &str = "ota.token";
+const MIN_TOKEN_LEN: usize = 16;
+pub const MAX_TOKEN_LEN: usize = 64;
+/// How often a deferred update looks at the pump and the clock again.
+const MAINTENANCE_POLL: Duration = Duration::from_secs(10);
+const MINUTES_PER_DAY: u16 = 24 * 60;
+/// Earlier clocks were not set by SNTP yet.
+const MIN_VALID_TIME: u64 = 1_672_531_200;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Channel {
+    Stable,
+    Beta,
+}
+
+impl Channel {
+    fn name(&self) -> &'static str {
+        match self {
+            Channel::Stable => "stable",
+            Channel::Beta => "beta",
+        }
+    }
+
+    /// Pre-release versions are only offered to beta devices.
+    fn accepts(&self, version: &Version) -> bool {
+        *self == Channel::Beta || version.pre.is_empty()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct OtaConfig {
+    pub manifest_url: String,
+    /// Seconds between manifest checks.
+    pub check_interval: u64,
+    pub channel: Channel,
+    /// Hold a downloaded image until the pump is off.
+    pub wait_for_idle: bool,
+    /// Only restart into a downloaded image inside this window.
+    pub window: Option<MaintenanceWindow>,
+}
+
+impl Default for OtaConfig {
+    fn default() -> Self {
+        Self {
+            manifest_url: DEFAULT_MANIFEST_URL.to_owned(),
+            check_interval: DEFAULT_CHECK_INTERVAL,
+            channel: Channel::Stable,
+            wait_for_idle: true,
+            window: None,
+        }
+    }
+}
+
+impl OtaConfig {
+    pub fn load(storage: &Storage) -> Self {
+        match storage.load::<OtaConfig>(CONFIG_KEY) {
+            Some(config) if config.is_valid() => config,
+            Some(config) => {
+                warn!("Invalid stored OTA config {:?}, using defaults", config);
+                Self::default()
+            }
+            None => Self::default(),
+        }
+    }
+
+    pub fn save(&self, storage: &Storage) -> Result<()> {
+        storage.store(CONFIG_KEY, self)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.manifest_url.starts_with("https://")
+            && self.check_interval >= MIN_CHECK_INTERVAL
+            && self
+                .window
+                .as_ref()
+                .map_or(true, MaintenanceWindow::is_valid)
+    }
+}
+
+/// Time of day in local minutes after midnight, `end` may be past midnight.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MaintenanceWindow {
+    pub start: u16,
+    pub end: u16,
+    /// Local time minus UTC, in minutes.
+    pub utc_offset: i16,
+}
+
+impl MaintenanceWindow {
+    fn is_valid(&self) -> bool {
+        self.start < MINUTES_PER_DAY
+            && self.end < MINUTES_PER_DAY
+            && self.start != self.end
+            && self.utc_offset.unsigned_abs() <= 14 * 60
+    }
+
+    fn contains(&self, unix_time: u64) -> bool {
+        let minute = (unix_time as i64 / 60 + self.utc_offset as i64)
+            .rem_euclid(MINUTES_PER_DAY as i64) as u16;
+        if self.start < self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+/// Why a downloaded image is not applied yet.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Deferral {
+    PumpRunning,
+    /// A window is set but SNTP did not sync the clock yet.
+    TimeNotSet,
+    OutsideWindow,
+}
+
+/// Lets the OTA task restart without cutting a pumping cycle short.
+pub trait SafeState: Send + 'static {
+    fn is_idle(&self) -> bool;
+    /// Switches the output off until the restart.
+    fn enter_safe_state(&self) -> Result<()>;
+}
+
+/// One signed entry per channel.
+#[derive(Deserialize, Debug)]
+struct Manifest {
+    #[serde(default)]
+    stable: Option<UpdateJson>,
+    #[serde(default)]
+    beta: Option<UpdateJson>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UpdateJson {
+    version: String,
+    link: String,
+    sha256: String,
+    size: usize,
+    signature: String,
+}
+
+#[derive(Debug)]
+pub struct Update {
+    version: Version,
+    link: String,
+    sha256: [u8; 32],
+    size: usize,
+}
+
+impl Update {
+    pub fn new(json: UpdateJson, channel: Channel) -> Result<Update> {
+        verify_signature(&json, channel)?;
+        let version = Version::parse(&json.version)?;
+        let link = json.link;
+        let sha256 = parse_hex(&json.sha256)?;
+        let size = json.size;
+        Ok(Update {
+            version,
+            link,
+            sha256,
+            size,
+        })
+    }
+}
+
+/// Must match `signed_message` in `tools/ota_sign`. The image is covered
+/// through its sha256, the channel keeps entries from being moved between
+/// channels.
+fn signed_message(json: &UpdateJson, channel: Channel) -> String {
+    format!(
+        "{}\n{}\n{}\n{}\n{}\n",
+        channel.name(),
+        json.version,
+        json.link,
+        json.size,
+        json.sha256
+    )
+}
+
+fn verify_signature(json: &UpdateJson, channel: Channel) -> Result<()> {
+    let key = VerifyingKey::from_bytes(&parse_hex(OTA_PUBLIC_KEY.trim())?)
+        .map_err(|_| anyhow!("Invalid OTA public key"))?;
+    let signature = Signature::from_bytes(&parse_hex(&json.signature)?);
+    key.verify_strict(signed_message(json, channel).as_bytes(), &signature)
+        .map_err(|_| anyhow!("Manifest signature does not match"))
+}
+
+fn parse_hex<const N: usize>(hex: &str) -> Result<[u8; N]> {
+    if hex.len() != N * 2 || !hex.is_ascii() {
+        bail!("Malformed hex string: {}", hex);
+    }
+    let mut bytes = [0_u8; N];
+    for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(str::from_utf8(pair)?, 16)?;
+    }
+    Ok(bytes)
+}
+
+/// Progress of the image download, shown in the status API.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct OtaStatus {
+    pub downloading: bool,
+    /// An image is being written or waits to be applied. Stays set until
+    /// the restart, so nothing else writes the update partition meanwhile.
+    pub installing: bool,
+    pub received: usize,
+    pub total: usize,
+    pub progress: u8,
+    pub error: Option<String>,
+    /// Version offered by the last manifest fetched.
+    pub available: Option<String>,
+    /// Last image that failed its self-test and was rolled back.
+    pub rollback: Option<Rollback>,
+    /// Uptime in seconds of the last manifest check.
+    pub last_check: Option<u64>,
+    pub last_result: Option<CheckResult>,
+    /// Consecutive failed checks.
+    pub failures: u32,
+    pub deferred: Option<Deferral>,
+}
+
+impl OtaStatus {
+    pub fn new(storage: &Storage) -> Self {
+        let rollback = storage.load::<Option<Rollback>>(ROLLBACK_KEY).flatten();
+        if let Some(rollback) = &rollback {
+            warn!(
+                "Version {} was rolled back: {}",
+                rollback.version, rollback.reason
+            );
+        }
+        Self {
+            rollback,
+            ..Default::default()
+        }
+    }
+
+    /// Whether `version` failed its self-test here before. It would fail
+    /// again, so it is skipped until a newer one comes out.
+    fn rolled_back(&self, version: &Version) -> bool {
+        self.rollback
+            .as_ref()
+            .and_then(|rollback| Version::parse(&rollback.version).ok())
+            .map_or(false, |rolled_back| rolled_back == *version)
+    }
+
+    /// Takes the update partition, checked and set under the same lock.
+    fn claim(&mut self) -> Result<()> {
+        if self.installing {
+            bail!("Another update is in progress");
+        }
+        self.installing = true;
+        Ok(())
+    }
+
+    fn begin(&mut self, total: usize) {
+        *self = OtaStatus {
+            downloading: true,
+            installing: true,
+            total,
+            available: self.available.take(),
+            rollback: self.rollback.take(),
+            last_check: self.last_check,
+            last_result: self.last_result.take(),
+            failures: self.failures,
+            ..Default::default()
+        };
+    }
+
+    fn advance(&mut self, received: usize) {
+        self.received = received;
+        if self.total > 0 {
+            self.progress = (received.min(self.total) * 100 / self.total) as u8;
+        }
+    }
+
+    fn fail(&mut self, error: &anyhow::Error) {
+        self.downloading = false;
+        self.error = Some(error.to_string());
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum CheckResult {
+    UpToDate,
+    Updating { version: String },
+    Failed { error: String },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Rollback {
+    pub version: String,
+    pub reason: String,
+}
+
+/// Checks a new image has to pass before it is marked valid.
+#[derive(Default)]
+pub struct SelfTest {
+    wifi: AtomicBool,
+    timer: AtomicBool,
+    server: AtomicBool,
+}
+
+impl SelfTest {
+    pub fn wifi_up(&self) {
+        self.wifi.store(true, Ordering::Relaxed);
+    }
+
+    pub fn timer_running(&self) {
+        self.timer.store(true, Ordering::Relaxed);
+    }
+
+    pub fn server_started(&self) {
+        self.server.store(true, Ordering::Relaxed);
+    }
+
+    fn pending(&self) -> Vec<&'static str> {
+        [
+            (&self.wifi, "Wi-Fi not connected"),
+            (&self.timer, "flow meter timer not running"),
+            (&self.server, "HTTP server not started"),
+        ]
+        .into_iter()
+        .filter(|(passed, _)| !passed.load(Ordering::Relaxed))
+        .map(|(_, check)| check)
+        .collect()
+    }
+}
+
+/// If the running image was just installed, waits in the background for
+/// the self-test and either marks the image valid or rolls back to the
+/// previous one. A crash or reset before that also rolls back, through
+/// the bootloader.
+pub fn verify_image(self_test: Arc<SelfTest>, storage: Storage) -> Result<()> {
+    if !pending_verify() {
+        return Ok(());
+    }
+    info!("New image pending verification");
+    // Overwritten once the self-test finishes, a reset before that keeps it
+    record_rollback(&storage, "Restarted before passing the self-test")?;
+
+    thread::Builder::new()
+        .name("verify".to_owned())
+        .stack_size(VERIFY_STACK_SIZE)
+        .spawn(move || {
+            let deadline = Instant::now() + SELF_TEST_WINDOW;
+            loop {
+                let pending = self_test.pending();
+                if pending.is_empty() {
+                    break;
+                }
+                if Instant::now() >= deadline {
+                    let reason = format!("Self-test failed: {}", pending.join(", "));
+                    warn!("{}, rolling back", reason);
+                    if let Err(err) = record_rollback(&storage, &reason) {
+                        warn!("Failed to record rollback: {}", err);
+                    }
+                    let err = esp!(unsafe { esp_ota_mark_app_invalid_rollback_and_reboot() });
+                    warn!("Rollback failed: {:?}", err);
+                    return;
+                }
+                thread::sleep(Duration::from_secs(1));
+            }
+            match esp!(unsafe { esp_ota_mark_app_valid_cancel_rollback() }) {
+                Ok(()) => info!("Self-test passed, image marked valid"),
+                Err(err) => warn!("Failed to mark image valid: {}", err),
+            }
+            if let Err(err) = storage.store::<Option<Rollback>>(ROLLBACK_KEY, &None) {
+                warn!("Failed to clear rollback record: {}", err);
+            }
+        })?;
+    Ok(())
+}
+
+fn pending_verify() -> bool {
+    let mut state = 0;
+    // Fails for images that were not installed through OTA
+    esp!(unsafe { esp_ota_get_state_partition(esp_ota_get_running_partition(), &mut state) })
+        .is_ok()
+        && state == esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY
+}
+
+fn record_rollback(storage: &Storage, reason: &str) -> Result<()> {
+    storage.store(
+        ROLLBACK_KEY,
+        &Some(Rollback {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            reason: reason.to_owned(),
+        }),
+    )
+}
+
+/// Version of the image that is currently running.
+pub fn running_version() -> Version {
+    Version::parse(env!("CARGO_PKG_VERSION")).expect("CARGO_PKG_VERSION is semver")
+}
+
+/// Starts the task that checks the manifest every `check_interval` and
+/// installs newer images. Failed checks are retried with exponential
+/// backoff, anything sent on `checks` triggers a check right away.
+pub fn spawn(
+    config: Arc<Mutex<OtaConfig>>,
+    status: Arc<Mutex<OtaStatus>>,
+    checks: Receiver<()>,
+    pump: impl SafeState,
+) -> Result<JoinHandle<()>> {
+    let task = thread::Builder::new()
+        .name("ota".to_owned())
+        .stack_size(TASK_STACK_SIZE)
+        .spawn(move || supervise(&config, &status, &checks, &pump))?;
+    Ok(task)
+}
+
+fn supervise(
+    config: &Mutex<OtaConfig>,
+    status: &Mutex<OtaStatus>,
+    checks: &Receiver<()>,
+    pump: &impl SafeState,
+) {
+    info!("Running version {}", running_version());
+    loop {
+        let result = check(config, status, pump);
+        let current = config.lock().unwrap().clone();
+
+        let mut status = status.lock().unwrap();
+        status.last_check = Some((unsafe { esp_timer_get_time() } / 1_000_000) as u64);
+        let wait = match result {
+            Ok(()) => {
+                status.failures = 0;
+                status.last_result = Some(CheckResult::UpToDate);
+                current.check_interval
+            }
+            Err(err) => {
+                status.failures += 1;
+                warn!("OTA check failed ({} in a row): {:#}", status.failures, err);
+                status.last_result = Some(CheckResult::Failed {
+                    error: format!("{:#}", err),
+                });
+                backoff(status.failures, current.check_interval)
+            }
+        };
+        drop(status);
+
+        match checks.recv_timeout(Duration::from_secs(wait)) {
+            Ok(()) => info!("OTA check requested"),
+            Err(RecvTimeoutError::Timeout) => {}
+            // Nobody can ask for checks anymore, keep the schedule
+            Err(RecvTimeoutError::Disconnected) => thread::sleep(Duration::from_secs(wait)),
+        }
+    }
+}
+
+/// Only returns if the device is up to date or something failed, installing
+/// an update restarts the device.
+fn check(
+    config: &Mutex<OtaConfig>,
+    status: &Mutex<OtaStatus>,
+    pump: &impl SafeState,
+) -> Result<()> {
+    let current = config.lock().unwrap().clone();
+    let update = check_update(&current)?;
+    info!("Manifest version {}", update.version);
+    status.lock().unwrap().available = Some(update.version.to_string());
+    if update.version <= running_version() {
+        return Ok(());
+    }
+    if status.lock().unwrap().rolled_back(&update.version) {
+        info!("Version {} was rolled back, skipping it", update.version);
+        return Ok(());
+    }
+    status.lock().unwrap().last_result = Some(CheckResult::Updating {
+        version: update.version.to_string(),
+    });
+    ota_update(&update, config, status, pump)
+}
+
+fn backoff(failures: u32, check_interval: u64) -> u64 {
+    let factor = 1_u64 << failures.saturating_sub(1).min(16);
+    RETRY_BACKOFF.saturating_mul(factor).min(check_interval)
+}
+
+fn connect() -> Result<Client<EspHttpConnection>> {
+    let connection = EspHttpConnection::new(&Configuration {
+        use_global_ca_store: true,
+        crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
+        timeout: Some(READ_TIMEOUT),
+        ..Default::default()
+    })?;
+    let client = Client::wrap(connection);
+
+    Ok(client)
+}
+
+fn check_update(config: &OtaConfig) -> Result<Update> {
+    select_update(fetch_manifest(&config.manifest_url)?, config)
+}
+
+/// Newest entry of the manifest the device's channel accepts. Beta devices
+/// also follow the stable channel.
+fn select_update(manifest: Manifest, config: &OtaConfig) -> Result<Update> {
+    let entries = match config.channel {
+        Channel::Stable => vec![(Channel::Stable, manifest.stable)],
+        Channel::Beta => vec![
+            (Channel::Stable, manifest.stable),
+            (Channel::Beta, manifest.beta),
+        ],
+    };
+
+    let mut newest: Option<Update> = None;
+    for (channel, json) in entries {
+        let Some(json) = json else {
+            continue;
+        };
+        let update = Update::new(json, channel)?;
+        if !config.channel.accepts(&update.version) {
+            warn!(
+                "Ignoring pre-release {} on the {} channel",
+                update.version,
+                config.channel.name()
+            );
+            continue;
+        }
+        if newest
+            .as_ref()
+            .map_or(true, |newest| update.version > newest.version)
+        {
+            newest = Some(update);
+        }
+    }
+    newest.ok_or_else(|| anyhow!("No {} entry in the manifest", config.channel.name()))
+}
+
+fn fetch_manifest(url: &str) -> Result<Manifest> {
+    let mut client = connect()?;
+    let request = client.get(url)?;
+    let response = request.submit()?;
+    let status = response.status();
+
+    let manifest: Manifest;
+
+    match status {
+        200..=299 => {
+            let mut buf = [0_u8; MAX_MANIFEST_LEN];
+            let mut reader = response;
+            let mut size = 0;
+            while size < buf.len() {
+                let read = Read::read(&mut reader, &mut buf[size..])?;
+                if read == 0 {
+                    break;
+                }
+                size += read;
+            }
+            if size == 0 {
+                bail!("Zero sized message");
+            }
+            manifest = serde_json::from_slice(&buf[..size])?;
+        }
+        _ => bail!("Unexpected response code: {}", status),
+    }
+
+    Ok(manifest)
+}
+
+fn ota_update(
+    update: &Update,
+    config: &Mutex<OtaConfig>,
+    status: &Mutex<OtaStatus>,
+    pump: &impl SafeState,
+) -> Result<()> {
+    status.lock().unwrap().claim()?;
+    let result = install(update, config, status, pump);
+    // Only failures get here, a successful install restarts
+    status.lock().unwrap().installing = false;
+    result
+}
+
+fn install(
+    update: &Update,
+    config: &Mutex<OtaConfig>,
+    status: &Mutex<OtaStatus>,
+    pump: &impl SafeState,
+) -> Result<()> {
+    let mut completed_ota = record(status, download(update, status))?;
+    apply(
+        &update.version,
+        move || Ok(completed_ota.set_as_boot_partition()?),
+        config,
+        status,
+        pump,
+    )
+}
+
+/// Waits until the pump and the maintenance window allow it, then boots
+/// into the new image. Only returns if `boot` failed.
+fn apply(
+    version: &Version,
+    boot: impl FnOnce() -> Result<()>,
+    config: &Mutex<OtaConfig>,
+    status: &Mutex<OtaStatus>,
+    pump: &impl SafeState,
+) -> Result<()> {
+    let mut deferred = None;
+    loop {
+        let reason = deferral(&config.lock().unwrap(), pump);
+        if reason != deferred {
+            if let Some(reason) = reason {
+                info!("Update to {} deferred: {:?}", version, reason);
+            }
+            deferred = reason;
+            status.lock().unwrap().deferred = reason;
+        }
+        if reason.is_none() {
+            break;
+        }
+        thread::sleep(MAINTENANCE_POLL);
+    }
+
+    // Before the pump goes down, a failure here leaves it running
+    boot()?;
+    if let Err(err) = pump.enter_safe_state() {
+        warn!("Pump did not confirm its safe state: {}", err);
+    }
+    info!("OTA Complete");
+    reset::restart();
+}
+
+fn deferral(config: &OtaConfig, pump: &impl SafeState) -> Option<Deferral> {
+    if config.wait_for_idle && !pump.is_idle() {
+        return Some(Deferral::PumpRunning);
+    }
+    let window = config.window.as_ref()?;
+    match unix_time() {
+        None => Some(Deferral::TimeNotSet),
+        Some(now) if !window.contains(now) => Some(Deferral::OutsideWindow),
+        Some(_) => None,
+    }
+}
+
+/// Seconds since the epoch, once SNTP set the clock.
+fn unix_time() -> Option<u64> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    (now >= MIN_VALID_TIME).then_some(now)
+}
+
+/// Flashes an image sent to the device itself. The body starts with the
+/// signed manifest on a single line, followed by the raw image, and goes
+/// through the same checks as a download. Images that are not newer than
+/// the running one are refused, so old signed images can't be replayed.
+/// Returns the version to hand to [`spawn_apply`] once the caller answered
+/// the request.
+pub fn upload<R>(
+    reader: &mut R,
+    content_len: Option<usize>,
+    config: &OtaConfig,
+    status: &Mutex<OtaStatus>,
+) -> Result<Version>
+where
+    R: Read<Error = EspIOError>,
+{
+    status.lock().unwrap().claim()?;
+    let result = install_upload(reader, content_len, config, status);
+    // Kept on success, the device restarts into the new image
+    if result.is_err() {
+        status.lock().unwrap().installing = false;
+    }
+    result
+}
+
+fn install_upload<R>(
+    reader: &mut R,
+    content_len: Option<usize>,
+    config: &OtaConfig,
+    status: &Mutex<OtaStatus>,
+) -> Result<Version>
+where
+    R: Read<Error = EspIOError>,
+{
+    let (update, manifest_len) = read_manifest_line(reader, config)?;
+    if update.version <= running_version() {
+        bail!(
+            "Version {} is not newer than the running {}",
+            update.version,
+            running_version()
+        );
+    }
+    if status.lock().unwrap().rolled_back(&update.version) {
+        bail!("Version {} was rolled back", update.version);
+    }
+    let image_len = content_len.map(|len| len.saturating_sub(manifest_len));
+    record(status, flash(&update, reader, image_len, status))?;
+    info!("Uploaded version {} written", update.version);
+    Ok(update.version)
+}
+
+/// Applies an uploaded image the same way as a downloaded one: deferred to
+/// an idle pump and the maintenance window, then through the pump's safe
+/// state into a restart.
+pub fn spawn_apply(
+    version: Version,
+    config: Arc<Mutex<OtaConfig>>,
+    status: Arc<Mutex<OtaStatus>>,
+    pump: impl SafeState,
+) -> Result<JoinHandle<()>> {
+    let task = thread::Builder::new()
+        .name("ota-apply".to_owned())
+        .stack_size(APPLY_STACK_SIZE)
+        .spawn(move || {
+            thread::sleep(APPLY_DELAY);
+            // `upload` finalized the image there and still holds the
+            // partition, so nothing else wrote to it since
+            let boot = || {
+                esp!(unsafe {
+                    esp_ota_set_boot_partition(esp_ota_get_next_update_partition(ptr::null()))
+                })?;
+                Ok(())
+            };
+            if let Err(err) = apply(&version, boot, &config, &status, &pump) {
+                warn!("Could not boot uploaded version {}: {}", version, err);
+                let mut status = status.lock().unwrap();
+                status.fail(&err);
+                status.installing = false;
+            }
+        })?;
+    Ok(task)
+}
+
+/// Upload token, `None` until one was set.
+fn upload_token(storage: &Storage) -> Option<String> {
+    storage.load(TOKEN_KEY)
+}
+
+/// Checks the `Authorization: Bearer <token>` header of an upload. Without
+/// a configured token nothing gets in.
+pub fn authorize(storage: &Storage, authorization: Option<&str>) -> Result<()> {
+    let Some(token) = upload_token(storage) else {
+        bail!("No upload token configured");
+    };
+    let given = authorization
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .unwrap_or("");
+    if !same_token(given.trim().as_bytes(), token.as_bytes()) {
+        bail!("Invalid upload token");
+    }
+    Ok(())
+}
+
+/// Replaces the token, which takes the current one. The first token only
+/// comes with the Wi-Fi provisioning, otherwise anyone on the LAN could
+/// claim a fresh device.
+pub fn set_upload_token(storage: &Storage, authorization: Option<&str>, token: &str) -> Result<()> {
+    if upload_token(storage).is_none() {
+        bail!("The first upload token is set while provisioning the Wi-Fi");
+    }
+    authorize(storage, authorization)?;
+    store_upload_token(storage, token)
+}
+
+/// The token the installer entered while provisioning the Wi-Fi. Replaces
+/// the current one, whoever provisions the device already controls it.
+pub fn provision_upload_token(storage: &Storage, token: &str) -> Result<()> {
+    store_upload_token(storage, token)
+}
+
+fn store_upload_token(storage: &Storage, token: &str) -> Result<()> {
+    check_upload_token(token)?;
+    storage.store(TOKEN_KEY, &token)
+}
+
+/// Whether `token` is acceptable as an upload token.
+pub fn check_upload_token(token: &str) -> Result<()> {
+    if token.len() < MIN_TOKEN_LEN || token.len() > MAX_TOKEN_LEN || !token.is_ascii() {
+        bail!(
+            "The token must be {} to {} ASCII characters",
+            MIN_TOKEN_LEN,
+            MAX_TOKEN_LEN
+        );
+    }
+    Ok(())
+}
+
+/// Compares in constant time, so the answer time doesn't leak the token.
+fn same_token(given: &[u8], token: &[u8]) -> bool {
+    given.len() == token.len()
+        && given
+            .iter()
+            .zip(token)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn read_manifest_line<R>(reader: &mut R, config: &OtaConfig) -> Result<(Update, usize)>
+where
+    R: Read<Error = EspIOError>,
+{
+    let mut line = Vec::new();
+    let mut byte = [0_u8; 1];
+    loop {
+        if Read::read(reader, &mut byte)? == 0 {
+            bail!("Missing image after the manifest");
+        }
+        if byte[0] == b'\n' {
+            break;
+        }
+        if line.len() == MAX_MANIFEST_LEN {
+            bail!("Manifest line longer than {} bytes", MAX_MANIFEST_LEN);
+        }
+        line.push(byte[0]);
+    }
+    let update = select_update(serde_json::from_slice(&line)?, config)?;
+    Ok((update, line.len() + 1))
+}
+
+fn record<T>(status: &Mutex<OtaStatus>, result: Result<T>) -> Result<T> {
+    let mut status = status.lock().unwrap();
+    match &result {
+        Ok(_) => status.downloading = false,
+        Err(err) => {
+            warn!("OTA aborted: {}", err);
+            status.fail(err);
+        }
+    }
+    result
+}
+
+fn download(update: &Update, status: &Mutex<OtaStatus>) -> Result<esp_ota::CompletedOtaUpdate> {
+    let mut client = connect()?;
+    let request = client.get(&update.link)?;
+    let mut response = request.submit()?;
+    let code = response.status();
+    if !(200..=299).contains(&code) {
+        bail!("Unexpected response code: {}", code);
+    }
+    let content_len = response.content_len().map(|len| len as usize);
+    flash(update, &mut response, content_len, status)
+}
+
+fn flash<R>(
+    update: &Update,
+    reader: &mut R,
+    content_len: Option<usize>,
+    status: &Mutex<OtaStatus>,
+) -> Result<esp_ota::CompletedOtaUpdate>
+where
+    R: Read<Error = EspIOError>,
+{
+    let total = content_len.unwrap_or(update.size);
+    if total != update.size {
+        bail!(
+            "Content-Length is {} bytes, expected {}",
+            total,
+            update.size
+        );
+    }
+    status.lock().unwrap().begin(total);
+
+    let mut ota = esp_ota::OtaUpdate::begin()?;
+    info!("Begin OTA");
+
+    match write_image(&mut ota, reader, update, status) {
+        Ok(()) => Ok(ota.finalize()?),
+        Err(err) => {
+            // Nothing was marked bootable, the running partition stays in place
+            let _ = ota.abort();
+            Err(err)
+        }
+    }
+}
+
+fn write_image<R>(
+    ota: &mut esp_ota::OtaUpdate,
+    reader: &mut R,
+    update: &Update,
+    status: &Mutex<OtaStatus>,
+) -> Result<()>
+where
+    R: Read<Error = EspIOError>,
+{
+    let started = Instant::now();
+    let mut buf = [0_u8; 256];
+    let mut hasher = Sha256::new();
+    let mut received = 0;
+    let mut logged_progress = 0;
+    loop {
+        if started.elapsed() > DOWNLOAD_TIMEOUT {
+            bail!("Download timed out after {} bytes", received);
+        }
+        // Fails once a read stalls for longer than READ_TIMEOUT
+        let size = Read::read(reader, &mut buf)?;
+        if size == 0 {
+            break;
+        }
+        received += size;
+        if received > update.size {
+            bail!("Image larger than the expected {} bytes", update.size);
+        }
+        hasher.update(&buf[..size]);
+        ota.write(&buf[..size])?;
+
+        let mut status = status.lock().unwrap();
+        status.advance(received);
+        if status.progress >= logged_progress + 10 {
+            logged_progress = status.progress;
+            info!(
+                "OTA {}% ({} of {} bytes)",
+                status.progress, received, status.total
+            );
+        }
+    }
+    if received != update.size {
+        bail!("Image is {} bytes, expected {}", received, update.size);
+    }
+    if hasher.finalize()[..] != update.sha256 {
+        bail!("Image sha256 does not match the manifest");
+    }
+    Ok(())
+}
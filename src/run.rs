@@ -1,32 +1,131 @@
 use anyhow::Result;
+use esp_idf_hal::gpio::IOPin;
+use log::{info, warn};
 use std::{
-    sync::{Arc, Mutex},
+    sync::{mpsc, Arc, Mutex},
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use crate::Pines;
+use crate::{
+    ota::{self, OtaConfig, OtaStatus, SelfTest},
+    storage::Storage,
+    subscription::subscribe_pin,
+    wifi::{Networks, WifiState, WifiStatus},
+    Pines,
+};
 
 use self::{
     flowmeter::{set_measurement_timer, FlowMeter},
-    pump::Pump,
+    pump::{press_button, Pump, PumpConfig, PumpHandle, PumpStatus},
+    server::ServerState,
 };
 
+mod api;
+mod calibration;
 mod flowmeter;
 mod pump;
 mod server;
 
-pub fn run(pins: Pines) -> Result<()> {
-    let state = Arc::new(Mutex::new(FlowMeter::new(pins.gpio32)?));
+const TOTALS_SAVE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+pub fn run(
+    pins: Pines,
+    storage: Storage,
+    ota_config: Arc<Mutex<OtaConfig>>,
+    ota_status: Arc<Mutex<OtaStatus>>,
+    self_test: Arc<SelfTest>,
+    wifi_networks: Arc<Mutex<Networks>>,
+    wifi_status: Arc<Mutex<WifiStatus>>,
+) -> Result<()> {
+    // The inlet meter comes first, it is the one that drives the pump
+    let flowmeters = vec![
+        Arc::new(Mutex::new(FlowMeter::new(
+            "entrada",
+            pins.gpio32.downgrade(),
+            storage.clone(),
+        )?)),
+        Arc::new(Mutex::new(FlowMeter::new(
+            "salida",
+            pins.gpio33.downgrade(),
+            storage.clone(),
+        )?)),
+    ];
+    let pump_config = Arc::new(Mutex::new(PumpConfig::load(&storage)));
+    let pump_status = Arc::new(Mutex::new(PumpStatus::default()));
+    let (pump_commands, commands) = mpsc::channel();
+    let (ota_checks, checks) = mpsc::channel();
+
+    // Applies updates only when the pump can be stopped safely
+    let _ota = ota::spawn(
+        ota_config.clone(),
+        ota_status.clone(),
+        checks,
+        PumpHandle {
+            status: pump_status.clone(),
+            commands: pump_commands.clone(),
+        },
+    )?;
 
-    let _server = server::begin(state.clone())?;
+    let server_state = Arc::new(ServerState {
+        flowmeters: flowmeters.clone(),
+        pump_config: pump_config.clone(),
+        pump_status: pump_status.clone(),
+        pump_commands: Mutex::new(pump_commands),
+        storage: storage.clone(),
+        ota_config,
+        ota_status,
+        ota_checks: Mutex::new(ota_checks),
+        wifi_networks,
+        wifi_status: wifi_status.clone(),
+    });
+    let mut server = Some(server::begin(server_state.clone())?);
+    self_test.server_started();
 
-    let _timer = set_measurement_timer(state.clone())?;
+    let _timer = set_measurement_timer(flowmeters.clone())?;
+    self_test.timer_running();
 
-    let mut pump = Pump::new(state, pins.gpio2, 1.0, 5.0)?;
+    // Cycles the pump between Auto, Force On and Force Off
+    let _button = subscribe_pin(pins.gpio4, press_button)?;
 
+    let mut pump = Pump::new(
+        flowmeters[0].clone(),
+        pins.gpio2,
+        pump_config,
+        pump_status,
+        commands,
+        storage,
+    )?;
+
+    let mut last_save = Instant::now();
     loop {
         pump.manage()?;
+        // A restart is coming, nothing counted since the last save may be lost
+        let shutdown_acks = pump.take_shutdown_acks();
+        if !shutdown_acks.is_empty() || last_save.elapsed() >= TOTALS_SAVE_INTERVAL {
+            for flowmeter in &flowmeters {
+                let mut flowmeter = flowmeter.lock().unwrap();
+                if let Err(err) = flowmeter.save_totals() {
+                    warn!("Failed to save {} totalizer: {}", flowmeter.get_name(), err);
+                }
+            }
+            last_save = Instant::now();
+        }
+        for ack in shutdown_acks {
+            let _ = ack.send(());
+        }
+
+        // The portal needs port 80, and its open AP mustn't reach the API
+        let portal = wifi_status.lock().unwrap().state == WifiState::Portal;
+        if portal && server.is_some() {
+            info!("App server stopped while the Wi-Fi portal is open");
+            server = None;
+        } else if !portal && server.is_none() {
+            match server::begin(server_state.clone()) {
+                Ok(started) => server = Some(started),
+                Err(err) => warn!("Could not restart the app server: {}", err),
+            }
+        }
         thread::sleep(Duration::from_secs(1));
     }
 }
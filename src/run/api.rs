@@ -0,0 +1,126 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use super::calibration::Calibration;
+use super::flowmeter::MeasurementConfig;
+use super::pump::{PumpConfig, PumpStatus};
+use crate::ota::{OtaConfig, OtaStatus};
+use crate::wifi::WifiStatus;
+
+/// Body of `GET /api/v1/status`.
+#[derive(Serialize, Debug)]
+pub struct StatusResponse {
+    /// Flow of the meter that drives the pump.
+    pub flow: f32,
+    pub meters: Vec<MeterStatus>,
+    pub pump: PumpStatus,
+    pub threshold_min: f32,
+    pub threshold_max: f32,
+    pub uptime: u64,
+    pub firmware_version: &'static str,
+    pub rssi: Option<i8>,
+    /// Link state, reconnects and why the link last dropped.
+    pub wifi: WifiStatus,
+    /// Progress of the last firmware download.
+    pub ota: OtaStatus,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MeterStatus {
+    pub name: &'static str,
+    pub flow: f32,
+    pub volume: f64,
+    pub trip_volume: f64,
+    pub calibrating: bool,
+}
+
+/// Settings exposed through `GET/PUT /api/v1/config`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Config {
+    pub pump: PumpConfig,
+    /// Keyed by meter name.
+    pub meters: BTreeMap<String, MeterConfig>,
+    pub ota: OtaConfig,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MeterConfig {
+    pub calibration: Calibration,
+    pub measurement: MeasurementConfig,
+}
+
+impl Config {
+    /// Applies a partial JSON document on top of the current settings.
+    /// Fields missing from `patch` keep their value, unknown fields are rejected.
+    pub fn patched(&self, patch: Value) -> Result<Config, String> {
+        let mut current = serde_json::to_value(self).map_err(|err| err.to_string())?;
+        merge(&mut current, patch, "")?;
+        serde_json::from_value(current).map_err(|err| err.to_string())
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.pump.is_valid()
+            && self
+                .meters
+                .values()
+                .all(|meter| meter.calibration.is_valid() && meter.measurement.is_valid())
+            && self.ota.is_valid()
+    }
+}
+
+/// Body of `POST /api/v1/calibration/finish`.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CalibrationRequest {
+    /// Defaults to the meter that drives the pump.
+    #[serde(default)]
+    pub meter: Option<String>,
+    /// Liters dispensed since the calibration was started.
+    pub volume: f32,
+}
+
+/// Entry of `GET /api/v1/wifi/networks`, passwords are never sent back.
+#[derive(Serialize, Debug)]
+pub struct WifiNetwork {
+    pub ssid: String,
+    pub priority: u8,
+}
+
+/// Body of `PUT /api/v1/ota/token`, no `Debug` so it can't end up in a log.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TokenRequest {
+    pub token: String,
+}
+
+/// Body of `DELETE /api/v1/wifi/networks`.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct WifiNetworkRemoval {
+    pub ssid: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ErrorResponse {
+    pub error: String,
+}
+
+fn merge(target: &mut Value, patch: Value, path: &str) -> Result<(), String> {
+    match (target, patch) {
+        (Value::Object(target), Value::Object(patch)) => {
+            for (key, value) in patch {
+                let field = format!("{}{}", path, key);
+                let slot = target
+                    .get_mut(&key)
+                    .ok_or_else(|| format!("unknown field `{}`", field))?;
+                merge(slot, value, &format!("{}.", field))?;
+            }
+            Ok(())
+        }
+        (target, patch) => {
+            *target = patch;
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use super::flowmeter::meter_key;
+use crate::storage::Storage;
+use anyhow::Result;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+const CALIBRATION_KEY: &str = "cal";
+const DEFAULT_K_FACTOR: f32 = 4.8;
+const MAX_CURVE_POINTS: usize = 16;
+/// Fewer pulses than this make a calibration run too coarse to trust.
+const MIN_CALIBRATION_PULSES: u64 = 100;
+
+/// A measured pulse frequency and the flow it corresponds to.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct CalibrationPoint {
+    pub frequency: f32,
+    pub flow: f32,
+}
+
+/// Converts pulse frequencies into flow for a given sensor.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Calibration {
+    /// Pulses per second for each L/min, as found on the sensor datasheet.
+    pub k_factor: f32,
+    /// Optional piecewise-linear correction, sorted by frequency. When
+    /// present it replaces `k_factor` for the flow reading.
+    pub curve: Vec<CalibrationPoint>,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self {
+            k_factor: DEFAULT_K_FACTOR,
+            curve: Vec::new(),
+        }
+    }
+}
+
+impl Calibration {
+    pub fn load(storage: &Storage, meter: &str) -> Self {
+        match storage.load::<Calibration>(&meter_key(meter, CALIBRATION_KEY)) {
+            Some(calibration) if calibration.is_valid() => calibration,
+            Some(calibration) => {
+                warn!(
+                    "Invalid stored calibration {:?}, using defaults",
+                    calibration
+                );
+                Self::default()
+            }
+            None => Self::default(),
+        }
+    }
+
+    pub fn save(&self, storage: &Storage, meter: &str) -> Result<()> {
+        storage.store(&meter_key(meter, CALIBRATION_KEY), self)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        let valid_point = |point: &CalibrationPoint| {
+            point.frequency.is_finite()
+                && point.flow.is_finite()
+                && point.frequency > 0.0
+                && point.flow >= 0.0
+        };
+        self.k_factor.is_finite()
+            && self.k_factor > 0.0
+            && self.curve.len() <= MAX_CURVE_POINTS
+            && self.curve.iter().all(valid_point)
+            && self
+                .curve
+                .windows(2)
+                .all(|pair| pair[0].frequency < pair[1].frequency)
+    }
+
+    /// Flow in L/min for a pulse frequency in Hz.
+    pub fn flow(&self, frequency: f32) -> f32 {
+        if self.curve.is_empty() {
+            return frequency / self.k_factor;
+        }
+
+        // The curve always starts at the origin and its last segment is
+        // extended past the highest calibrated point.
+        let origin = CalibrationPoint {
+            frequency: 0.0,
+            flow: 0.0,
+        };
+        let mut lower = origin;
+        let mut upper = self.curve[0];
+        for point in &self.curve[1..] {
+            if frequency <= upper.frequency {
+                break;
+            }
+            lower = upper;
+            upper = *point;
+        }
+        let slope = (upper.flow - lower.flow) / (upper.frequency - lower.frequency);
+        (lower.flow + slope * (frequency - lower.frequency)).max(0.0)
+    }
+
+    /// Liters for a number of pulses, using the K-factor. The curve only
+    /// corrects the flow reading, the totals keep the K-factor's scale.
+    pub fn volume(&self, pulses: u64) -> f64 {
+        pulses as f64 / (self.k_factor as f64 * 60.0)
+    }
+
+    /// Derives the K-factor from the pulses counted while dispensing a
+    /// known volume in liters.
+    pub fn k_factor_for(pulses: u64, volume: f32) -> Option<f32> {
+        if pulses < MIN_CALIBRATION_PULSES || !volume.is_finite() || volume <= 0.0 {
+            return None;
+        }
+        Some(pulses as f32 / (volume * 60.0))
+    }
+}
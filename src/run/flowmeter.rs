@@ -1,49 +1,279 @@
-static PULSE_COUNT: AtomicU32 = AtomicU32::new(0);
 const MEASUREMENT_INTERVAL: u64 = 3;
-const PULSES_PER_LITER_PER_MINUTE: f32 = 4.8;
+const TOTALS_KEY: &str = "totals";
+const MEASUREMENT_KEY: &str = "meas";
+/// Names prefix the NVS keys of each meter, which are limited to 15 bytes.
+const MAX_NAME_LEN: usize = 8;
+const DEFAULT_PERIOD_TIMEOUT_MS: u32 = 10_000;
+/// In auto mode, windows with at least this many pulses rely only on counting.
+const AUTO_BLEND_PULSES: u32 = 20;
 
-use anyhow::Result;
+use super::calibration::Calibration;
+use crate::storage::Storage;
+use anyhow::{bail, Result};
 use esp_idf_hal::gpio::*;
 use esp_idf_hal::peripheral::Peripheral;
 use esp_idf_svc::timer::*;
 use esp_idf_sys::*;
-use std::sync::{atomic::*, Arc, Mutex};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+#[cfg(not(feature = "pcnt"))]
+mod interrupt;
+#[cfg(feature = "pcnt")]
+mod pcnt;
+
+#[cfg(not(feature = "pcnt"))]
+use interrupt::PulseCounter;
+#[cfg(feature = "pcnt")]
+use pcnt::PulseCounter;
+
+/// Raw pulse counts, so that volumes never accumulate rounding errors.
+/// The pulses are folded into liters when the K-factor changes, so a new
+/// calibration doesn't rescale what was already measured.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(default)]
+struct Totals {
+    lifetime_pulses: u64,
+    trip_pulses: u64,
+    /// Liters measured with earlier K-factors.
+    lifetime_liters: f64,
+    trip_liters: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MeasurementMode {
+    /// Pulses counted over the measurement interval.
+    Counting,
+    /// Time between the last two pulses, better suited to low flows.
+    Period,
+    /// Period at low flows, counting as pulses become plentiful.
+    Auto,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct MeasurementConfig {
+    /// Ignored by the `pcnt` backend, which can only count.
+    pub mode: MeasurementMode,
+    /// Without a pulse for this long the period mode reports zero flow.
+    pub period_timeout_ms: u32,
+}
+
+impl Default for MeasurementConfig {
+    fn default() -> Self {
+        Self {
+            mode: MeasurementMode::Counting,
+            period_timeout_ms: DEFAULT_PERIOD_TIMEOUT_MS,
+        }
+    }
+}
+
+impl MeasurementConfig {
+    pub fn load(storage: &Storage, meter: &str) -> Self {
+        match storage.load::<MeasurementConfig>(&meter_key(meter, MEASUREMENT_KEY)) {
+            Some(config) if config.is_valid() => config,
+            Some(config) => {
+                warn!(
+                    "Invalid stored measurement config {:?} for {}, using defaults",
+                    config, meter
+                );
+                Self::default()
+            }
+            None => Self::default(),
+        }
+    }
+
+    pub fn save(&self, storage: &Storage, meter: &str) -> Result<()> {
+        storage.store(&meter_key(meter, MEASUREMENT_KEY), self)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        // Longer timeouts would not survive the timestamp wrap around
+        self.period_timeout_ms > 0 && self.period_timeout_ms <= 3_600_000
+    }
+}
+
 pub struct FlowMeter<P>
 where
     P: Pin,
 {
+    name: &'static str,
     flow: f32,
-    _pin: PinDriver<'static, P, Input>,
+    totals: Totals,
+    saved_totals: Totals,
+    calibration: Calibration,
+    calibration_start: Option<u64>,
+    measurement: MeasurementConfig,
+    storage: Storage,
+    counter: PulseCounter<P>,
 }
 
 impl<P: InputPin + OutputPin> FlowMeter<P> {
-    pub fn new(pin: impl Peripheral<P = P> + 'static) -> Result<Self> {
+    pub fn new(
+        name: &'static str,
+        pin: impl Peripheral<P = P> + 'static,
+        storage: Storage,
+    ) -> Result<Self> {
+        if name.is_empty() || name.len() > MAX_NAME_LEN {
+            bail!(
+                "Flow meter name {:?} must be 1 to {} bytes",
+                name,
+                MAX_NAME_LEN
+            );
+        }
+        let totals = storage
+            .load(&meter_key(name, TOTALS_KEY))
+            .unwrap_or_default();
         Ok(Self {
+            name,
             flow: 0.0,
-            _pin: subscribe_pin(pin, count_pulse)?,
+            totals,
+            saved_totals: totals,
+            calibration: Calibration::load(&storage, name),
+            calibration_start: None,
+            measurement: MeasurementConfig::load(&storage, name),
+            storage,
+            counter: PulseCounter::new(pin)?,
         })
     }
 
+    pub fn get_name(&self) -> &'static str {
+        self.name
+    }
+
     pub fn get_flow(&self) -> f32 {
         self.flow
     }
 
-    fn set_flow(&mut self, flow: f32) {
-        self.flow = flow;
+    /// Liters measured since the totalizer was first started.
+    pub fn get_volume(&self) -> f64 {
+        self.totals.lifetime_liters + self.calibration.volume(self.totals.lifetime_pulses)
+    }
+
+    /// Liters measured since the last trip reset.
+    pub fn get_trip_volume(&self) -> f64 {
+        self.totals.trip_liters + self.calibration.volume(self.totals.trip_pulses)
+    }
+
+    pub fn reset_trip(&mut self) -> Result<()> {
+        self.totals.trip_pulses = 0;
+        self.totals.trip_liters = 0.0;
+        self.save_totals()
+    }
+
+    /// Writes the totals to NVS if they changed since the last save.
+    pub fn save_totals(&mut self) -> Result<()> {
+        if self.totals != self.saved_totals {
+            self.storage
+                .store(&meter_key(self.name, TOTALS_KEY), &self.totals)?;
+            self.saved_totals = self.totals;
+        }
+        Ok(())
+    }
+
+    pub fn get_calibration(&self) -> &Calibration {
+        &self.calibration
+    }
+
+    /// A new K-factor only applies to the pulses counted from now on.
+    pub fn set_calibration(&mut self, calibration: Calibration) -> Result<()> {
+        calibration.save(&self.storage, self.name)?;
+        if calibration.k_factor != self.calibration.k_factor {
+            self.fold_totals();
+        }
+        self.calibration = calibration;
+        self.save_totals()
+    }
+
+    pub fn get_measurement(&self) -> MeasurementConfig {
+        self.measurement
+    }
+
+    pub fn set_measurement(&mut self, measurement: MeasurementConfig) -> Result<()> {
+        measurement.save(&self.storage, self.name)?;
+        self.measurement = measurement;
+        Ok(())
+    }
+
+    pub fn is_calibrating(&self) -> bool {
+        self.calibration_start.is_some()
+    }
+
+    /// Starts counting pulses for a "dispense a known volume" calibration.
+    pub fn start_calibration(&mut self) {
+        self.calibration_start = Some(self.pulses());
+    }
+
+    /// Ends the calibration run started by `start_calibration` and stores
+    /// the K-factor matching the dispensed `volume` in liters. Returns
+    /// `None` if not enough pulses were counted to compute it.
+    pub fn finish_calibration(&mut self, volume: f32) -> Result<Option<f32>> {
+        let Some(start) = self.calibration_start.take() else {
+            return Ok(None);
+        };
+        let pulses = self.pulses().saturating_sub(start);
+        let Some(k_factor) = Calibration::k_factor_for(pulses, volume) else {
+            return Ok(None);
+        };
+        self.set_calibration(Calibration {
+            k_factor,
+            ..self.calibration.clone()
+        })?;
+        Ok(Some(k_factor))
+    }
+
+    /// Turns the counted pulses into liters with the current calibration.
+    fn fold_totals(&mut self) {
+        let folded = self.totals.lifetime_pulses;
+        self.totals.lifetime_liters += self.calibration.volume(folded);
+        self.totals.trip_liters += self.calibration.volume(self.totals.trip_pulses);
+        self.totals.lifetime_pulses = 0;
+        self.totals.trip_pulses = 0;
+        // A calibration run keeps counting from the same pulse
+        self.calibration_start = self
+            .calibration_start
+            .map(|start| start.saturating_sub(folded));
+    }
+
+    /// Lifetime pulses since the last K-factor change, including the ones
+    /// not yet taken by the timer.
+    fn pulses(&self) -> u64 {
+        self.totals.lifetime_pulses + self.counter.pending() as u64
+    }
+
+    fn measure(&mut self) {
+        let cnt = self.counter.take();
+        self.totals.lifetime_pulses += cnt as u64;
+        self.totals.trip_pulses += cnt as u64;
+
+        let counted = cnt as f32 / MEASUREMENT_INTERVAL as f32;
+        let timed = self
+            .counter
+            .last_period(self.measurement.period_timeout_ms)
+            .map_or(0.0, |period| 1_000_000.0 / period as f32);
+        let frequency = match self.measurement.mode {
+            _ if !PulseCounter::<P>::TIMES_EDGES => counted,
+            MeasurementMode::Counting => counted,
+            MeasurementMode::Period => timed,
+            MeasurementMode::Auto => {
+                let weight = cnt.min(AUTO_BLEND_PULSES) as f32 / AUTO_BLEND_PULSES as f32;
+                weight * counted + (1.0 - weight) * timed
+            }
+        };
+        self.flow = self.calibration.flow(frequency);
     }
 }
 
 pub fn set_measurement_timer<P: InputPin + OutputPin>(
-    flowmeter_arc: Arc<Mutex<FlowMeter<P>>>,
+    flowmeters: Vec<Arc<Mutex<FlowMeter<P>>>>,
 ) -> Result<EspTimer, EspError> {
     let periodic_timer = EspTimerService::new()?.timer(move || {
-        let cnt = PULSE_COUNT.fetch_and(0, Ordering::Relaxed);
-        let mut flowmeter = flowmeter_arc.lock().unwrap();
-        flowmeter.set_flow(
-            cnt as f32 / (PULSES_PER_LITER_PER_MINUTE * (MEASUREMENT_INTERVAL as u32) as f32),
-        );
+        for flowmeter in &flowmeters {
+            flowmeter.lock().unwrap().measure();
+        }
     })?;
 
     periodic_timer.every(Duration::from_secs(MEASUREMENT_INTERVAL))?;
@@ -51,20 +281,7 @@ pub fn set_measurement_timer<P: InputPin + OutputPin>(
     Ok(periodic_timer)
 }
 
-fn count_pulse() {
-    PULSE_COUNT.fetch_add(1, Ordering::Relaxed);
-}
-
-fn subscribe_pin<'d, P: InputPin + OutputPin>(
-    pin: impl Peripheral<P = P> + 'd,
-    notify: impl Fn() + 'static,
-) -> Result<PinDriver<'d, P, Input>> {
-    let mut pin = PinDriver::input(pin)?;
-
-    pin.set_interrupt_type(InterruptType::NegEdge)?;
-
-    unsafe {
-        pin.subscribe(notify)?;
-    }
-    Ok(pin)
+/// NVS key for a setting that belongs to the meter called `name`.
+pub fn meter_key(name: &str, key: &str) -> String {
+    format!("{}.{}", name, key)
 }
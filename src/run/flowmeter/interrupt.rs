@@ -0,0 +1,100 @@
+use anyhow::Result;
+use esp_idf_hal::gpio::*;
+use esp_idf_hal::peripheral::Peripheral;
+use esp_idf_sys::*;
+use std::sync::{atomic::*, Arc};
+
+/// Shared between a `PulseCounter` and its interrupt handler.
+#[derive(Default)]
+struct Pulses {
+    count: AtomicU32,
+    // Microsecond timestamps truncated to 32 bits, they wrap every ~71 minutes
+    last_edge: AtomicU32,
+    last_period: AtomicU32,
+}
+
+impl Pulses {
+    fn count(&self) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let now = unsafe { esp_timer_get_time() } as u32;
+        let previous = self.last_edge.swap(now, Ordering::Relaxed);
+        if previous != 0 {
+            self.last_period
+                .store(now.wrapping_sub(previous), Ordering::Relaxed);
+        }
+    }
+}
+
+/// Counts pulses with a GPIO interrupt on every falling edge.
+pub struct PulseCounter<P>
+where
+    P: Pin,
+{
+    pulses: Arc<Pulses>,
+    _pin: PinDriver<'static, P, Input>,
+}
+
+impl<P: InputPin + OutputPin> PulseCounter<P> {
+    /// Edges are timestamped, so the period modes are available.
+    pub const TIMES_EDGES: bool = true;
+
+    pub fn new(pin: impl Peripheral<P = P> + 'static) -> Result<Self> {
+        let pulses = Arc::new(Pulses::default());
+        let notified = pulses.clone();
+        Ok(Self {
+            pulses,
+            _pin: subscribe_pin(pin, move || notified.count())?,
+        })
+    }
+
+    /// Returns the pulses counted since the last call.
+    pub fn take(&mut self) -> u32 {
+        self.pulses.count.fetch_and(0, Ordering::Relaxed)
+    }
+
+    /// Pulses counted since the last `take`, without consuming them.
+    pub fn pending(&self) -> u32 {
+        self.pulses.count.load(Ordering::Relaxed)
+    }
+
+    /// Time in microseconds between the last two pulses, or `None` if the
+    /// flow stopped for longer than `timeout_ms`. Once the pulses stop it
+    /// grows with the time since the last one, so the flow decays right away.
+    pub fn last_period(&self, timeout_ms: u32) -> Option<u32> {
+        let timeout = timeout_ms.saturating_mul(1000);
+        let now = unsafe { esp_timer_get_time() } as u32;
+        let since_edge = now.wrapping_sub(self.pulses.last_edge.load(Ordering::Relaxed));
+        let period = self.pulses.last_period.load(Ordering::Relaxed);
+        if period == 0 || period > timeout {
+            None
+        } else if since_edge > timeout {
+            // Forget it, once the timestamps wrap `since_edge` would look
+            // recent again and bring the old period back
+            let _ = self.pulses.last_period.compare_exchange(
+                period,
+                0,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+            None
+        } else {
+            Some(period.max(since_edge))
+        }
+    }
+}
+
+fn subscribe_pin<'d, P: InputPin + OutputPin>(
+    pin: impl Peripheral<P = P> + 'd,
+    notify: impl Fn() + Send + 'static,
+) -> Result<PinDriver<'d, P, Input>> {
+    let mut pin = PinDriver::input(pin)?;
+
+    // Keeps unwired meter inputs from floating and counting noise
+    pin.set_pull(Pull::Up)?;
+    pin.set_interrupt_type(InterruptType::NegEdge)?;
+
+    unsafe {
+        pin.subscribe(notify)?;
+    }
+    Ok(pin)
+}
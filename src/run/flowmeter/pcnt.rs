@@ -0,0 +1,144 @@
+static OVERFLOWS: [AtomicU32; PCNT_UNITS] = [
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+];
+static NEXT_UNIT: AtomicU32 = AtomicU32::new(0);
+const PCNT_UNITS: usize = 8;
+/// The hardware counter is 16 bits wide, it wraps back to zero here.
+const COUNTER_LIMIT: i16 = i16::MAX;
+/// Pulses shorter than this many APB cycles (80 MHz) are ignored, 1023 is
+/// the largest value the glitch filter accepts (~12.8 us).
+const GLITCH_FILTER: u16 = 1023;
+/// Reads to wait for the overflow interrupt after the counter wrapped.
+const WRAP_RETRIES: u32 = 100;
+
+use anyhow::{bail, Result};
+use esp_idf_hal::gpio::*;
+use esp_idf_hal::peripheral::{Peripheral, PeripheralRef};
+use esp_idf_sys::*;
+use std::ffi::c_void;
+use std::sync::atomic::*;
+
+/// Counts pulses with the PCNT peripheral, without any per-edge interrupt.
+pub struct PulseCounter<P>
+where
+    P: Pin,
+{
+    _pin: PeripheralRef<'static, P>,
+    unit: pcnt_unit_t,
+    last_total: u64,
+}
+
+impl<P: InputPin + OutputPin> PulseCounter<P> {
+    /// The hardware only counts, edges carry no timestamps.
+    pub const TIMES_EDGES: bool = false;
+
+    pub fn new(pin: impl Peripheral<P = P> + 'static) -> Result<Self> {
+        let pin = pin.into_ref();
+        let unit = NEXT_UNIT.fetch_add(1, Ordering::Relaxed) as pcnt_unit_t;
+        if unit as usize >= PCNT_UNITS {
+            bail!("No PCNT unit left for GPIO{}", pin.pin());
+        }
+
+        let config = pcnt_config_t {
+            pulse_gpio_num: pin.pin(),
+            ctrl_gpio_num: PCNT_PIN_NOT_USED,
+            lctrl_mode: pcnt_ctrl_mode_t_PCNT_MODE_KEEP,
+            hctrl_mode: pcnt_ctrl_mode_t_PCNT_MODE_KEEP,
+            pos_mode: pcnt_count_mode_t_PCNT_COUNT_DIS,
+            neg_mode: pcnt_count_mode_t_PCNT_COUNT_INC,
+            counter_h_lim: COUNTER_LIMIT,
+            counter_l_lim: 0,
+            unit,
+            channel: pcnt_channel_t_PCNT_CHANNEL_0,
+        };
+
+        unsafe {
+            esp!(pcnt_unit_config(&config))?;
+            esp!(pcnt_set_filter_value(unit, GLITCH_FILTER))?;
+            esp!(pcnt_filter_enable(unit))?;
+            esp!(pcnt_event_enable(unit, pcnt_evt_type_t_PCNT_EVT_H_LIM))?;
+            esp!(pcnt_counter_pause(unit))?;
+            esp!(pcnt_counter_clear(unit))?;
+            // Already installed is fine, another unit may have done it
+            let installed = pcnt_isr_service_install(0);
+            if installed != ESP_ERR_INVALID_STATE as esp_err_t {
+                esp!(installed)?;
+            }
+            esp!(pcnt_isr_handler_add(
+                unit,
+                Some(count_overflow),
+                unit as usize as *mut c_void,
+            ))?;
+            esp!(pcnt_counter_resume(unit))?;
+        }
+
+        Ok(Self {
+            _pin: pin,
+            unit,
+            last_total: 0,
+        })
+    }
+
+    /// Returns the pulses counted since the last call.
+    pub fn take(&mut self) -> u32 {
+        let total = self.total();
+        let pulses = total.saturating_sub(self.last_total);
+        self.last_total = total;
+        pulses as u32
+    }
+
+    /// Pulses counted since the last `take`, without consuming them.
+    pub fn pending(&self) -> u32 {
+        self.total().saturating_sub(self.last_total) as u32
+    }
+
+    pub fn last_period(&self, _timeout_ms: u32) -> Option<u32> {
+        None
+    }
+
+    /// Pulses since the unit was configured, including wrapped counts.
+    /// Never less than at the last `take`.
+    fn total(&self) -> u64 {
+        let overflows = &OVERFLOWS[self.unit as usize];
+        let mut retries = 0;
+        loop {
+            let before = overflows.load(Ordering::Acquire);
+            let mut value: i16 = 0;
+            let _ = esp!(unsafe { pcnt_get_counter_value(self.unit, &mut value) });
+            // Read again in case the counter wrapped while we looked at it
+            if overflows.load(Ordering::Acquire) != before {
+                continue;
+            }
+            let total = before as u64 * COUNTER_LIMIT as u64 + value.max(0) as u64;
+            if total >= self.last_total {
+                return total;
+            }
+            // The counter is back at zero but `count_overflow` hasn't run
+            // yet. The pulses aren't lost, the next `take` picks them up.
+            retries += 1;
+            if retries >= WRAP_RETRIES {
+                return self.last_total;
+            }
+        }
+    }
+}
+
+impl<P: Pin> Drop for PulseCounter<P> {
+    fn drop(&mut self) {
+        unsafe {
+            pcnt_counter_pause(self.unit);
+            pcnt_isr_handler_remove(self.unit);
+        }
+    }
+}
+
+unsafe extern "C" fn count_overflow(arg: *mut c_void) {
+    OVERFLOWS[arg as usize].fetch_add(1, Ordering::Release);
+}
@@ -1,8 +1,232 @@
 use super::flowmeter::FlowMeter;
+use crate::{ota::SafeState, storage::Storage};
 use anyhow::Result;
 use esp_idf_hal::gpio::*;
 use esp_idf_hal::peripheral::Peripheral;
-use std::sync::{Arc, Mutex};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc::{self, Receiver, Sender, TryRecvError},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant};
+
+static BUTTON_PRESSED: AtomicBool = AtomicBool::new(false);
+const CONFIG_KEY: &str = "pump";
+const FAULT_KEY: &str = "pump.fault";
+const MODE_KEY: &str = "pump.mode";
+const MAX_TIMED_ON_MINUTES: u32 = 24 * 60;
+const DEFAULT_THRESHOLD_MIN: f32 = 1.0;
+const DEFAULT_THRESHOLD_MAX: f32 = 5.0;
+const DEFAULT_DRY_RUN_FLOW: f32 = 0.5;
+const DEFAULT_PRIMING_TIME: u32 = 30;
+const DEFAULT_RETRY_BACKOFF: u32 = 60;
+const DEFAULT_MAX_FAILURES: u32 = 3;
+const DEFAULT_MIN_RUN_TIME: u32 = 10;
+const DEFAULT_MIN_REST_TIME: u32 = 10;
+const DEFAULT_MAX_STARTS_PER_HOUR: u32 = 20;
+const HOUR: Duration = Duration::from_secs(3600);
+/// Upper bound for the doubling retry backoff, in seconds.
+const MAX_RETRY_BACKOFF: u32 = 3600;
+/// `manage` runs every second, so the pump answers a shutdown well before this.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct PumpConfig {
+    pub threshold_min: f32,
+    pub threshold_max: f32,
+    /// Flow the pump must reach by the end of the priming time, in L/min.
+    pub dry_run_flow: f32,
+    /// Seconds after a start before the flow is checked for a dry run.
+    pub priming_time: u32,
+    /// Seconds to rest after a dry run, doubled on every further failure.
+    pub retry_backoff: u32,
+    /// Consecutive dry runs that latch a fault until acknowledged.
+    pub max_failures: u32,
+    /// Seconds the pump stays on once started, unless it runs dry.
+    pub min_run_time: u32,
+    /// Seconds the pump stays off once stopped.
+    pub min_rest_time: u32,
+    /// Starts allowed in any sliding hour, 0 for no limit.
+    pub max_starts_per_hour: u32,
+    /// Keep the operator mode across reboots instead of starting in Auto.
+    pub restore_mode: bool,
+}
+
+impl Default for PumpConfig {
+    fn default() -> Self {
+        Self {
+            threshold_min: DEFAULT_THRESHOLD_MIN,
+            threshold_max: DEFAULT_THRESHOLD_MAX,
+            dry_run_flow: DEFAULT_DRY_RUN_FLOW,
+            priming_time: DEFAULT_PRIMING_TIME,
+            retry_backoff: DEFAULT_RETRY_BACKOFF,
+            max_failures: DEFAULT_MAX_FAILURES,
+            min_run_time: DEFAULT_MIN_RUN_TIME,
+            min_rest_time: DEFAULT_MIN_REST_TIME,
+            max_starts_per_hour: DEFAULT_MAX_STARTS_PER_HOUR,
+            restore_mode: false,
+        }
+    }
+}
+
+impl PumpConfig {
+    pub fn with_thresholds(self, threshold_min: f32, threshold_max: f32) -> Self {
+        Self {
+            threshold_min: min(threshold_min, threshold_max),
+            threshold_max: max(threshold_min, threshold_max),
+            ..self
+        }
+    }
+
+    /// Loads the stored thresholds, falling back to the compiled defaults.
+    pub fn load(storage: &Storage) -> Self {
+        match storage.load::<PumpConfig>(CONFIG_KEY) {
+            Some(config) if config.is_valid() => {
+                config.with_thresholds(config.threshold_min, config.threshold_max)
+            }
+            Some(config) => {
+                warn!("Invalid stored pump config {:?}, using defaults", config);
+                Self::default()
+            }
+            None => Self::default(),
+        }
+    }
+
+    pub fn save(&self, storage: &Storage) -> Result<()> {
+        storage.store(CONFIG_KEY, self)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        [self.threshold_min, self.threshold_max, self.dry_run_flow]
+            .iter()
+            .all(|flow| flow.is_finite() && *flow >= 0.0)
+            && self.max_failures > 0
+    }
+
+    fn backoff(&self, failures: u32) -> Duration {
+        let factor = 1_u32 << failures.saturating_sub(1).min(16);
+        Duration::from_secs(
+            self.retry_backoff
+                .saturating_mul(factor)
+                .min(MAX_RETRY_BACKOFF) as u64,
+        )
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PumpState {
+    #[default]
+    Idle,
+    /// Started, waiting for the flow to prove there is water.
+    Priming,
+    Running,
+    /// Too many dry runs in a row, stopped until acknowledged.
+    DryRunFault,
+    /// Resting after a dry run before it may start again.
+    Cooldown,
+}
+
+/// Operator override of the automatic control.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum PumpMode {
+    #[default]
+    Auto,
+    /// On regardless of flow, still within the dry run protection and the
+    /// anti-short-cycle guards.
+    ForceOn,
+    /// Off once the minimum run time allows it.
+    ForceOff,
+    /// Like `ForceOn` for some minutes, then back to Auto.
+    TimedOn {
+        minutes: u32,
+    },
+}
+
+impl PumpMode {
+    pub fn is_valid(&self) -> bool {
+        match self {
+            PumpMode::TimedOn { minutes } => (1..=MAX_TIMED_ON_MINUTES).contains(minutes),
+            _ => true,
+        }
+    }
+
+    /// The mode the physical button switches to.
+    fn next(&self) -> PumpMode {
+        match self {
+            PumpMode::Auto => PumpMode::ForceOn,
+            PumpMode::ForceOn => PumpMode::ForceOff,
+            PumpMode::ForceOff | PumpMode::TimedOn { .. } => PumpMode::Auto,
+        }
+    }
+}
+
+/// Why the pump is being held in its current state.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockReason {
+    MinRunTime,
+    MinRestTime,
+    MaxStartsPerHour,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, Default)]
+pub struct PumpStatus {
+    pub on: bool,
+    pub state: PumpState,
+    /// Consecutive dry runs since the last successful start.
+    pub failures: u32,
+    pub blocked: Option<BlockReason>,
+    pub mode: PumpMode,
+    /// Seconds left of a timed override.
+    pub mode_remaining: Option<u64>,
+    /// Held off until the next restart.
+    pub shut_down: bool,
+}
+
+/// Requests for the pump, applied on its next `manage`.
+#[derive(Debug)]
+pub enum PumpCommand {
+    AcknowledgeFault,
+    SetMode(PumpMode),
+    /// Switches the output off until the next restart, answered once the pin
+    /// is low and the totalizers are saved.
+    Shutdown(Sender<()>),
+}
+
+/// Puts the pump in its safe state ahead of a restart.
+pub fn shut_down(commands: &Sender<PumpCommand>) -> Result<()> {
+    let (ack, done) = mpsc::channel();
+    commands.send(PumpCommand::Shutdown(ack))?;
+    done.recv_timeout(SHUTDOWN_TIMEOUT)?;
+    Ok(())
+}
+
+/// What the OTA task sees of the pump.
+pub struct PumpHandle {
+    pub status: Arc<Mutex<PumpStatus>>,
+    pub commands: Sender<PumpCommand>,
+}
+
+impl SafeState for PumpHandle {
+    fn is_idle(&self) -> bool {
+        !self.status.lock().unwrap().on
+    }
+
+    fn enter_safe_state(&self) -> Result<()> {
+        shut_down(&self.commands)
+    }
+}
+
+/// Registers a press of the mode button, safe to call from an interrupt.
+pub fn press_button() {
+    BUTTON_PRESSED.store(true, Ordering::Relaxed);
+}
 
 pub struct Pump<P, I>
 where
@@ -11,8 +235,20 @@ where
 {
     state: Arc<Mutex<FlowMeter<I>>>,
     pin: PinDriver<'static, P, Output>,
-    threshold_min: f32,
-    threshold_max: f32,
+    config: Arc<Mutex<PumpConfig>>,
+    status: Arc<Mutex<PumpStatus>>,
+    commands: Receiver<PumpCommand>,
+    storage: Storage,
+    pump_state: PumpState,
+    since: Instant,
+    failures: u32,
+    starts: VecDeque<Instant>,
+    stopped: Option<Instant>,
+    blocked: Option<BlockReason>,
+    mode: PumpMode,
+    mode_until: Option<Instant>,
+    shut_down: bool,
+    shutdown_acks: Vec<Sender<()>>,
 }
 
 fn min(a: f32, b: f32) -> f32 {
@@ -35,25 +271,247 @@ impl<P: InputPin + OutputPin, I: InputPin + OutputPin> Pump<P, I> {
     pub fn new(
         state: Arc<Mutex<FlowMeter<I>>>,
         pin: impl Peripheral<P = P> + 'static,
-        threshold_min: f32,
-        threshold_max: f32,
+        config: Arc<Mutex<PumpConfig>>,
+        status: Arc<Mutex<PumpStatus>>,
+        commands: Receiver<PumpCommand>,
+        storage: Storage,
     ) -> Result<Self> {
-        Ok(Self {
+        // A latched fault must survive a reboot
+        let pump_state = if storage.load::<bool>(FAULT_KEY).unwrap_or(false) {
+            warn!("Pump dry run fault still latched");
+            PumpState::DryRunFault
+        } else {
+            PumpState::Idle
+        };
+        let restore_mode = config.lock().unwrap().restore_mode;
+        let mode = if restore_mode {
+            storage
+                .load::<PumpMode>(MODE_KEY)
+                .filter(PumpMode::is_valid)
+                .unwrap_or_default()
+        } else {
+            PumpMode::Auto
+        };
+        let mut pump = Self {
             state,
             pin: PinDriver::output(pin)?,
-            threshold_min: min(threshold_min, threshold_max),
-            threshold_max: max(threshold_min, threshold_max),
-        })
+            config,
+            status,
+            commands,
+            storage,
+            pump_state,
+            since: Instant::now(),
+            failures: 0,
+            starts: VecDeque::new(),
+            stopped: None,
+            blocked: None,
+            mode: PumpMode::Auto,
+            mode_until: None,
+            shut_down: false,
+            shutdown_acks: Vec::new(),
+        };
+        pump.set_mode(mode);
+        Ok(pump)
     }
 
     pub fn manage(&mut self) -> Result<()> {
+        self.handle_commands();
+        if BUTTON_PRESSED.swap(false, Ordering::Relaxed) {
+            self.set_mode(self.mode.next());
+        }
+        if self
+            .mode_until
+            .map_or(false, |until| Instant::now() >= until)
+        {
+            self.set_mode(PumpMode::Auto);
+        }
+
         let flow = self.state.lock().unwrap().get_flow();
-        if flow > self.threshold_max {
+        let config = *self.config.lock().unwrap();
+        self.blocked = None;
+
+        let on = !self.shut_down && {
+            self.control(flow, &config);
+            matches!(self.pump_state, PumpState::Priming | PumpState::Running)
+        };
+
+        if on {
             self.pin.set_high()?;
-        }
-        if flow < self.threshold_min {
+        } else {
+            if self.pin.is_set_high() {
+                self.stopped = Some(Instant::now());
+            }
             self.pin.set_low()?;
         }
+
+        let mut status = self.status.lock().unwrap();
+        status.on = self.pin.is_set_high();
+        status.state = self.pump_state;
+        status.failures = self.failures;
+        status.blocked = self.blocked;
+        status.mode = self.mode;
+        status.mode_remaining = self
+            .mode_until
+            .map(|until| until.saturating_duration_since(Instant::now()).as_secs());
+        status.shut_down = self.shut_down;
         Ok(())
     }
+
+    /// Shutdown requests answered by the last `manage`, with the output
+    /// already low. The caller acknowledges them once it saved what must
+    /// survive the restart.
+    pub fn take_shutdown_acks(&mut self) -> Vec<Sender<()>> {
+        std::mem::take(&mut self.shutdown_acks)
+    }
+
+    /// The overrides only replace the flow thresholds as the demand, the
+    /// dry run protection and the guards apply in every mode.
+    fn control(&mut self, flow: f32, config: &PumpConfig) {
+        let demand = match self.mode {
+            PumpMode::Auto => None,
+            PumpMode::ForceOn | PumpMode::TimedOn { .. } => Some(true),
+            PumpMode::ForceOff => Some(false),
+        };
+        let elapsed = self.since.elapsed();
+        match self.pump_state {
+            PumpState::Idle => {
+                if demand.unwrap_or(flow > config.threshold_max) {
+                    self.start(config);
+                }
+            }
+            PumpState::Priming => {
+                if demand == Some(false) {
+                    self.stop(config);
+                } else if elapsed >= Duration::from_secs(config.priming_time as u64) {
+                    if flow < config.dry_run_flow {
+                        self.dry_run(config);
+                    } else if demand.unwrap_or(flow >= config.threshold_min) {
+                        self.failures = 0;
+                        self.enter(PumpState::Running);
+                    } else {
+                        self.stop(config);
+                    }
+                }
+            }
+            PumpState::Running => match demand {
+                // Nothing else would ever stop a forced pump running dry
+                Some(true) if flow < config.dry_run_flow => self.dry_run(config),
+                Some(true) => {}
+                Some(false) => self.stop(config),
+                None => {
+                    if flow < config.threshold_min {
+                        self.stop(config);
+                    }
+                }
+            },
+            PumpState::Cooldown => {
+                if elapsed >= config.backoff(self.failures) {
+                    self.enter(PumpState::Idle);
+                }
+            }
+            PumpState::DryRunFault => {}
+        }
+    }
+
+    fn handle_commands(&mut self) {
+        loop {
+            match self.commands.try_recv() {
+                Ok(PumpCommand::AcknowledgeFault) => {
+                    if self.pump_state == PumpState::DryRunFault {
+                        self.save_fault(false);
+                        self.failures = 0;
+                        self.enter(PumpState::Idle);
+                    }
+                }
+                Ok(PumpCommand::SetMode(mode)) => self.set_mode(mode),
+                Ok(PumpCommand::Shutdown(ack)) => {
+                    if !self.shut_down {
+                        info!("Pump shut down for a restart");
+                    }
+                    self.shut_down = true;
+                    self.shutdown_acks.push(ack);
+                }
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => return,
+            }
+        }
+    }
+
+    fn set_mode(&mut self, mode: PumpMode) {
+        info!("Pump mode {:?} -> {:?}", self.mode, mode);
+        self.mode_until = match mode {
+            PumpMode::TimedOn { minutes } => {
+                Some(Instant::now() + Duration::from_secs(minutes as u64 * 60))
+            }
+            _ => None,
+        };
+        self.mode = mode;
+        // The new mode applies anyway, it just won't survive a reboot
+        if let Err(err) = self.storage.store(MODE_KEY, &mode) {
+            warn!("Could not save the pump mode: {}", err);
+        }
+    }
+
+    fn dry_run(&mut self, config: &PumpConfig) {
+        self.failures += 1;
+        warn!("Pump dry run {} of {}", self.failures, config.max_failures);
+        if self.failures >= config.max_failures {
+            self.save_fault(true);
+            self.enter(PumpState::DryRunFault);
+        } else {
+            self.enter(PumpState::Cooldown);
+        }
+    }
+
+    /// A full NVS only costs the latch across reboots, the pump keeps being
+    /// protected meanwhile.
+    fn save_fault(&self, latched: bool) {
+        if let Err(err) = self.storage.store(FAULT_KEY, &latched) {
+            warn!("Could not save the pump fault latch: {}", err);
+        }
+    }
+
+    /// Idle to Priming, unless the anti-short-cycle guard holds it off.
+    fn start(&mut self, config: &PumpConfig) {
+        while let Some(start) = self.starts.front() {
+            if start.elapsed() < HOUR {
+                break;
+            }
+            self.starts.pop_front();
+        }
+
+        let rest = Duration::from_secs(config.min_rest_time as u64);
+        if self
+            .stopped
+            .map_or(false, |stopped| stopped.elapsed() < rest)
+        {
+            self.blocked = Some(BlockReason::MinRestTime);
+        } else if config.max_starts_per_hour > 0
+            && self.starts.len() >= config.max_starts_per_hour as usize
+        {
+            self.blocked = Some(BlockReason::MaxStartsPerHour);
+        } else {
+            self.starts.push_back(Instant::now());
+            self.enter(PumpState::Priming);
+        }
+    }
+
+    /// Back to Idle on low demand, once the minimum run time is over.
+    fn stop(&mut self, config: &PumpConfig) {
+        let run = Duration::from_secs(config.min_run_time as u64);
+        if self
+            .starts
+            .back()
+            .map_or(false, |start| start.elapsed() < run)
+        {
+            self.blocked = Some(BlockReason::MinRunTime);
+        } else {
+            self.enter(PumpState::Idle);
+        }
+    }
+
+    fn enter(&mut self, pump_state: PumpState) {
+        info!("Pump {:?} -> {:?}", self.pump_state, pump_state);
+        self.pump_state = pump_state;
+        self.since = Instant::now();
+    }
 }
@@ -1,30 +1,593 @@
 use anyhow::Result;
-use embedded_svc::{http::Method, io::Write};
-use esp_idf_svc::http::server::{Configuration, EspHttpServer};
-use std::sync::{Arc, Mutex};
+use embedded_svc::{
+    http::{server::Request, Headers, Method},
+    io::{Read, Write},
+};
 use esp_idf_hal::gpio::*;
+use esp_idf_svc::http::server::{Configuration, EspHttpConnection, EspHttpServer};
+use esp_idf_sys::esp_timer_get_time;
+use serde::Serialize;
+use std::sync::{mpsc::Sender, Arc, Mutex};
 
+use super::api::{
+    CalibrationRequest, Config, ErrorResponse, MeterConfig, MeterStatus, StatusResponse,
+    TokenRequest, WifiNetwork, WifiNetworkRemoval,
+};
 use super::flowmeter::FlowMeter;
+use super::pump::{PumpCommand, PumpConfig, PumpHandle, PumpMode, PumpStatus};
+use crate::{
+    ota::{self, OtaConfig, OtaStatus},
+    storage::Storage,
+    wifi::{self, Networks, SavedNetwork, WifiStatus},
+};
 
-pub fn begin<'d, P: InputPin + OutputPin>(
-    server_state_viewer: Arc<Mutex<FlowMeter<P>>>,
-) -> Result<EspHttpServer> {
+/// Room for a full `Config` with every meter carrying a 16 point
+/// calibration curve, which a client may GET and PUT back unchanged.
+const MAX_BODY_LEN: usize = 4096;
+
+pub struct ServerState<P: Pin> {
+    /// The first meter is the one that drives the pump.
+    pub flowmeters: Vec<Arc<Mutex<FlowMeter<P>>>>,
+    pub pump_config: Arc<Mutex<PumpConfig>>,
+    pub pump_status: Arc<Mutex<PumpStatus>>,
+    pub pump_commands: Mutex<Sender<PumpCommand>>,
+    pub storage: Storage,
+    pub ota_config: Arc<Mutex<OtaConfig>>,
+    pub ota_status: Arc<Mutex<OtaStatus>>,
+    pub ota_checks: Mutex<Sender<()>>,
+    pub wifi_networks: Arc<Mutex<Networks>>,
+    pub wifi_status: Arc<Mutex<WifiStatus>>,
+}
+
+impl<P: InputPin + OutputPin> ServerState<P> {
+    fn flowmeter(&self, name: Option<&str>) -> Option<&Arc<Mutex<FlowMeter<P>>>> {
+        match name {
+            Some(name) => self
+                .flowmeters
+                .iter()
+                .find(|flowmeter| flowmeter.lock().unwrap().get_name() == name),
+            None => self.flowmeters.first(),
+        }
+    }
+
+    fn status(&self) -> StatusResponse {
+        let config = *self.pump_config.lock().unwrap();
+        let meters: Vec<MeterStatus> = self
+            .flowmeters
+            .iter()
+            .map(|flowmeter| {
+                let flowmeter = flowmeter.lock().unwrap();
+                MeterStatus {
+                    name: flowmeter.get_name(),
+                    flow: flowmeter.get_flow(),
+                    volume: flowmeter.get_volume(),
+                    trip_volume: flowmeter.get_trip_volume(),
+                    calibrating: flowmeter.is_calibrating(),
+                }
+            })
+            .collect();
+        StatusResponse {
+            flow: meters.first().map_or(0.0, |meter| meter.flow),
+            meters,
+            pump: *self.pump_status.lock().unwrap(),
+            threshold_min: config.threshold_min,
+            threshold_max: config.threshold_max,
+            uptime: (unsafe { esp_timer_get_time() } / 1_000_000) as u64,
+            firmware_version: env!("CARGO_PKG_VERSION"),
+            rssi: wifi::rssi(),
+            wifi: self.wifi_status.lock().unwrap().clone(),
+            ota: self.ota_status.lock().unwrap().clone(),
+        }
+    }
+
+    fn command(&self, command: PumpCommand) -> Result<()> {
+        self.pump_commands.lock().unwrap().send(command)?;
+        Ok(())
+    }
+
+    fn check_for_update(&self) -> Result<()> {
+        self.ota_checks.lock().unwrap().send(())?;
+        Ok(())
+    }
+
+    fn wifi_networks(&self) -> Vec<WifiNetwork> {
+        self.wifi_networks
+            .lock()
+            .unwrap()
+            .list()
+            .iter()
+            .map(|network| WifiNetwork {
+                ssid: network.ssid.clone(),
+                priority: network.priority,
+            })
+            .collect()
+    }
+
+    fn config(&self) -> Config {
+        let pump = *self.pump_config.lock().unwrap();
+        let meters = self
+            .flowmeters
+            .iter()
+            .map(|flowmeter| {
+                let flowmeter = flowmeter.lock().unwrap();
+                let config = MeterConfig {
+                    calibration: flowmeter.get_calibration().clone(),
+                    measurement: flowmeter.get_measurement(),
+                };
+                (flowmeter.get_name().to_string(), config)
+            })
+            .collect();
+        let ota = self.ota_config.lock().unwrap().clone();
+        Config { pump, meters, ota }
+    }
+
+    fn set_config(&self, config: Config) -> Result<()> {
+        // Same order the form and `PumpConfig::load` put them in
+        let pump = config
+            .pump
+            .with_thresholds(config.pump.threshold_min, config.pump.threshold_max);
+        pump.save(&self.storage)?;
+        *self.pump_config.lock().unwrap() = pump;
+        for (name, meter) in config.meters {
+            let Some(flowmeter) = self.flowmeter(Some(&name)) else {
+                continue;
+            };
+            let mut flowmeter = flowmeter.lock().unwrap();
+            if *flowmeter.get_calibration() != meter.calibration {
+                flowmeter.set_calibration(meter.calibration)?;
+            }
+            if flowmeter.get_measurement() != meter.measurement {
+                flowmeter.set_measurement(meter.measurement)?;
+            }
+        }
+        let mut ota_config = self.ota_config.lock().unwrap();
+        if *ota_config != config.ota {
+            config.ota.save(&self.storage)?;
+            *ota_config = config.ota;
+        }
+        Ok(())
+    }
+}
+
+/// Started again whenever the Wi-Fi portal closes, so it takes the state
+/// shared.
+pub fn begin<P: InputPin + OutputPin>(state: Arc<ServerState<P>>) -> Result<EspHttpServer> {
     // 1.Create a `EspHttpServer` instance using a default configuration
-    let mut server = EspHttpServer::new(&Configuration::default())?;
+    let mut server = EspHttpServer::new(&Configuration {
+        // Verifying and flashing uploads needs more than the default stack
+        stack_size: 10240,
+        ..Default::default()
+    })?;
 
     // 2. Write a handler that returns the index page
+    let viewer = state.clone();
     server.fn_handler("/", Method::Get, move |request| {
-        let current_state = server_state_viewer.lock().unwrap();
-        let html = index_html(current_state.get_flow());
+        let html = index_html(&viewer.status());
         let mut response = request.into_ok_response()?;
         response.write_all(html.as_bytes())?;
         Ok(())
     })?;
 
+    // 3. Accept the thresholds form and persist it
+    let editor = state.clone();
+    server.fn_handler("/config", Method::Post, move |mut request| {
+        let Some(body) = read_body(&mut request)? else {
+            request.into_status_response(413)?;
+            return Ok(());
+        };
+        let body = core::str::from_utf8(&body).unwrap_or("");
+
+        let threshold_min = form_value(body, "threshold_min").and_then(|v| v.parse().ok());
+        let threshold_max = form_value(body, "threshold_max").and_then(|v| v.parse().ok());
+        let current = editor.config();
+        let pump = match (threshold_min, threshold_max) {
+            (Some(min), Some(max)) => current.pump.with_thresholds(min, max),
+            _ => {
+                request.into_status_response(400)?;
+                return Ok(());
+            }
+        };
+        if !pump.is_valid() {
+            request.into_status_response(400)?;
+            return Ok(());
+        }
+
+        editor.set_config(Config { pump, ..current })?;
+
+        request.into_response(303, Some("See Other"), &[("Location", "/")])?;
+        Ok(())
+    })?;
+
+    let editor = state.clone();
+    server.fn_handler("/trip/reset", Method::Post, move |mut request| {
+        let Some(body) = read_body(&mut request)? else {
+            request.into_status_response(413)?;
+            return Ok(());
+        };
+        let body = core::str::from_utf8(&body).unwrap_or("");
+        let Some(flowmeter) = editor.flowmeter(form_value(body, "meter")) else {
+            request.into_status_response(404)?;
+            return Ok(());
+        };
+        flowmeter.lock().unwrap().reset_trip()?;
+        request.into_response(303, Some("See Other"), &[("Location", "/")])?;
+        Ok(())
+    })?;
+
+    let editor = state.clone();
+    server.fn_handler("/pump/ack", Method::Post, move |request| {
+        editor.command(PumpCommand::AcknowledgeFault)?;
+        request.into_response(303, Some("See Other"), &[("Location", "/")])?;
+        Ok(())
+    })?;
+
+    let editor = state.clone();
+    server.fn_handler("/pump/mode", Method::Post, move |mut request| {
+        let Some(body) = read_body(&mut request)? else {
+            request.into_status_response(413)?;
+            return Ok(());
+        };
+        let body = core::str::from_utf8(&body).unwrap_or("");
+        let mode = match form_value(body, "mode") {
+            Some("auto") => PumpMode::Auto,
+            Some("force_on") => PumpMode::ForceOn,
+            Some("force_off") => PumpMode::ForceOff,
+            Some("timed_on") => match form_value(body, "minutes").and_then(|v| v.parse().ok()) {
+                Some(minutes) => PumpMode::TimedOn { minutes },
+                None => {
+                    request.into_status_response(400)?;
+                    return Ok(());
+                }
+            },
+            _ => {
+                request.into_status_response(400)?;
+                return Ok(());
+            }
+        };
+        if !mode.is_valid() {
+            request.into_status_response(400)?;
+            return Ok(());
+        }
+        editor.command(PumpCommand::SetMode(mode))?;
+        request.into_response(303, Some("See Other"), &[("Location", "/")])?;
+        Ok(())
+    })?;
+
+    // 4. JSON API for scripts and dashboards
+    let viewer = state.clone();
+    server.fn_handler("/api/v1/status", Method::Get, move |request| {
+        json_response(request, 200, &viewer.status())?;
+        Ok(())
+    })?;
+
+    let viewer = state.clone();
+    server.fn_handler("/api/v1/config", Method::Get, move |request| {
+        json_response(request, 200, &viewer.config())?;
+        Ok(())
+    })?;
+
+    let viewer = state.clone();
+    server.fn_handler("/api/v1/wifi/networks", Method::Get, move |request| {
+        json_response(request, 200, &viewer.wifi_networks())?;
+        Ok(())
+    })?;
+
+    // Adds a network, or updates the one with the same SSID
+    let editor = state.clone();
+    server.fn_handler("/api/v1/wifi/networks", Method::Put, move |mut request| {
+        let Some(body) = read_body(&mut request)? else {
+            json_error(request, 413, "request body too large")?;
+            return Ok(());
+        };
+        let network: SavedNetwork = match serde_json::from_slice(&body) {
+            Ok(network) => network,
+            Err(err) => {
+                json_error(request, 400, err)?;
+                return Ok(());
+            }
+        };
+        if let Err(err) = editor.wifi_networks.lock().unwrap().add(network) {
+            json_error(request, 422, err)?;
+            return Ok(());
+        }
+        json_response(request, 200, &editor.wifi_networks())?;
+        Ok(())
+    })?;
+
+    let editor = state.clone();
+    server.fn_handler(
+        "/api/v1/wifi/networks",
+        Method::Delete,
+        move |mut request| {
+            let Some(body) = read_body(&mut request)? else {
+                json_error(request, 413, "request body too large")?;
+                return Ok(());
+            };
+            let removal: WifiNetworkRemoval = match serde_json::from_slice(&body) {
+                Ok(removal) => removal,
+                Err(err) => {
+                    json_error(request, 400, err)?;
+                    return Ok(());
+                }
+            };
+            if !editor.wifi_networks.lock().unwrap().remove(&removal.ssid)? {
+                json_error(request, 404, "unknown network")?;
+                return Ok(());
+            }
+            json_response(request, 200, &editor.wifi_networks())?;
+            Ok(())
+        },
+    )?;
+
+    // Resets the trip counter of `?meter=<name>`, or of every meter
+    let editor = state.clone();
+    server.fn_handler("/api/v1/totalizer/reset", Method::Post, move |request| {
+        let name = query_value(request.uri(), "meter").map(str::to_owned);
+        let flowmeters = match name {
+            Some(name) => match editor.flowmeter(Some(&name)) {
+                Some(flowmeter) => vec![flowmeter.clone()],
+                None => {
+                    json_error(request, 404, format!("unknown meter `{}`", name))?;
+                    return Ok(());
+                }
+            },
+            None => editor.flowmeters.clone(),
+        };
+        for flowmeter in flowmeters {
+            flowmeter.lock().unwrap().reset_trip()?;
+        }
+        json_response(request, 200, &editor.status())?;
+        Ok(())
+    })?;
+
+    let editor = state.clone();
+    server.fn_handler("/api/v1/pump/ack", Method::Post, move |request| {
+        editor.command(PumpCommand::AcknowledgeFault)?;
+        json_response(request, 202, &editor.status())?;
+        Ok(())
+    })?;
+
+    let editor = state.clone();
+    server.fn_handler("/api/v1/pump/mode", Method::Put, move |mut request| {
+        let Some(body) = read_body(&mut request)? else {
+            json_error(request, 413, "request body too large")?;
+            return Ok(());
+        };
+        let mode: PumpMode = match serde_json::from_slice(&body) {
+            Ok(mode) => mode,
+            Err(err) => {
+                json_error(request, 400, err)?;
+                return Ok(());
+            }
+        };
+        if !mode.is_valid() {
+            json_error(request, 422, "invalid pump mode")?;
+            return Ok(());
+        }
+        editor.command(PumpCommand::SetMode(mode))?;
+        json_response(request, 202, &editor.status())?;
+        Ok(())
+    })?;
+
+    let editor = state.clone();
+    server.fn_handler("/api/v1/calibration/start", Method::Post, move |request| {
+        let name = query_value(request.uri(), "meter").map(str::to_owned);
+        let Some(flowmeter) = editor.flowmeter(name.as_deref()) else {
+            json_error(
+                request,
+                404,
+                format!("unknown meter `{}`", name.unwrap_or_default()),
+            )?;
+            return Ok(());
+        };
+        let mut flowmeter = flowmeter.lock().unwrap();
+        flowmeter.start_calibration();
+        let calibration = flowmeter.get_calibration().clone();
+        drop(flowmeter);
+        json_response(request, 200, &calibration)?;
+        Ok(())
+    })?;
+
+    let editor = state.clone();
+    server.fn_handler(
+        "/api/v1/calibration/finish",
+        Method::Post,
+        move |mut request| {
+            let Some(body) = read_body(&mut request)? else {
+                json_error(request, 413, "request body too large")?;
+                return Ok(());
+            };
+            let calibration: CalibrationRequest = match serde_json::from_slice(&body) {
+                Ok(calibration) => calibration,
+                Err(err) => {
+                    json_error(request, 400, err)?;
+                    return Ok(());
+                }
+            };
+            let Some(flowmeter) = editor.flowmeter(calibration.meter.as_deref()) else {
+                json_error(request, 404, "unknown meter")?;
+                return Ok(());
+            };
+            let mut flowmeter = flowmeter.lock().unwrap();
+            if !flowmeter.is_calibrating() {
+                json_error(request, 409, "no calibration in progress")?;
+                return Ok(());
+            }
+            if flowmeter.finish_calibration(calibration.volume)?.is_none() {
+                json_error(request, 422, "not enough pulses for the given volume")?;
+                return Ok(());
+            }
+            let calibration = flowmeter.get_calibration().clone();
+            drop(flowmeter);
+            json_response(request, 200, &calibration)?;
+            Ok(())
+        },
+    )?;
+
+    let checker = state.clone();
+    server.fn_handler("/ota/check", Method::Post, move |request| {
+        checker.check_for_update()?;
+        request.into_response(303, Some("See Other"), &[("Location", "/")])?;
+        Ok(())
+    })?;
+
+    let checker = state.clone();
+    server.fn_handler("/api/v1/ota/check", Method::Post, move |request| {
+        checker.check_for_update()?;
+        json_response(request, 202, &checker.ota_status.lock().unwrap().clone())?;
+        Ok(())
+    })?;
+
+    // Replaces the bearer token uploads need, the first one comes with the
+    // Wi-Fi provisioning
+    let editor = state.clone();
+    server.fn_handler("/api/v1/ota/token", Method::Put, move |mut request| {
+        let Some(body) = read_body(&mut request)? else {
+            json_error(request, 413, "request body too large")?;
+            return Ok(());
+        };
+        let token: TokenRequest = match serde_json::from_slice(&body) {
+            Ok(token) => token,
+            Err(err) => {
+                json_error(request, 400, err)?;
+                return Ok(());
+            }
+        };
+        let authorization = request.header("Authorization");
+        if let Err(err) = ota::set_upload_token(&editor.storage, authorization, &token.token) {
+            json_error(request, 403, err)?;
+            return Ok(());
+        }
+        request.into_status_response(204)?;
+        Ok(())
+    })?;
+
+    // The token authenticates the caller, the signed manifest on the first
+    // line of the body the image
+    let uploader = state.clone();
+    server.fn_handler("/api/v1/ota", Method::Post, move |mut request| {
+        if let Err(err) = ota::authorize(&uploader.storage, request.header("Authorization")) {
+            json_error(request, 401, err)?;
+            return Ok(());
+        }
+        let content_len = request.content_len().map(|len| len as usize);
+        let config = uploader.ota_config.lock().unwrap().clone();
+        let version = match ota::upload(&mut request, content_len, &config, &uploader.ota_status) {
+            Ok(version) => version,
+            Err(err) => {
+                json_error(request, 400, err)?;
+                return Ok(());
+            }
+        };
+        // Applied like a downloaded update, the restart waits for the pump
+        // and the maintenance window
+        let pump = PumpHandle {
+            status: uploader.pump_status.clone(),
+            commands: uploader.pump_commands.lock().unwrap().clone(),
+        };
+        let applied = ota::spawn_apply(
+            version,
+            uploader.ota_config.clone(),
+            uploader.ota_status.clone(),
+            pump,
+        );
+        if let Err(err) = applied {
+            uploader.ota_status.lock().unwrap().installing = false;
+            json_error(request, 500, err)?;
+            return Ok(());
+        }
+        json_response(request, 202, &uploader.ota_status.lock().unwrap().clone())?;
+        Ok(())
+    })?;
+
+    let editor = state;
+    server.fn_handler("/api/v1/config", Method::Put, move |mut request| {
+        let Some(body) = read_body(&mut request)? else {
+            json_error(request, 413, "request body too large")?;
+            return Ok(());
+        };
+        let patch = match serde_json::from_slice(&body) {
+            Ok(patch) => patch,
+            Err(err) => {
+                json_error(request, 400, err)?;
+                return Ok(());
+            }
+        };
+        let config = match editor.config().patched(patch) {
+            Ok(config) => config,
+            Err(err) => {
+                json_error(request, 400, err)?;
+                return Ok(());
+            }
+        };
+        if !config.is_valid() {
+            json_error(request, 422, "invalid settings")?;
+            return Ok(());
+        }
+
+        editor.set_config(config)?;
+        json_response(request, 200, &editor.config())?;
+        Ok(())
+    })?;
+
     println!("Server awaiting connection");
     Ok(server)
 }
 
+/// Reads the whole request body, or returns `None` if it exceeds `MAX_BODY_LEN`.
+fn read_body(request: &mut Request<&mut EspHttpConnection>) -> Result<Option<Vec<u8>>> {
+    let len = request.content_len().unwrap_or(0) as usize;
+    if len > MAX_BODY_LEN {
+        return Ok(None);
+    }
+    let mut body = vec![0_u8; len];
+    let mut read = 0;
+    while read < len {
+        let size = request.read(&mut body[read..])?;
+        if size == 0 {
+            break;
+        }
+        read += size;
+    }
+    body.truncate(read);
+    Ok(Some(body))
+}
+
+fn json_response<T: Serialize>(
+    request: Request<&mut EspHttpConnection>,
+    status: u16,
+    body: &T,
+) -> Result<()> {
+    let json = serde_json::to_vec(body)?;
+    let mut response =
+        request.into_response(status, None, &[("Content-Type", "application/json")])?;
+    response.write_all(&json)?;
+    Ok(())
+}
+
+fn json_error(
+    request: Request<&mut EspHttpConnection>,
+    status: u16,
+    error: impl ToString,
+) -> Result<()> {
+    let body = ErrorResponse {
+        error: error.to_string(),
+    };
+    json_response(request, status, &body)
+}
+
+fn query_value<'a>(uri: &'a str, key: &str) -> Option<&'a str> {
+    let (_, query) = uri.split_once('?')?;
+    form_value(query, key)
+}
+
+fn form_value<'a>(body: &'a str, key: &str) -> Option<&'a str> {
+    body.split('&').find_map(|pair| {
+        let (name, value) = pair.split_once('=')?;
+        (name == key).then_some(value)
+    })
+}
+
 fn templated(content: impl AsRef<str>) -> String {
     format!(
         r#"
@@ -34,15 +597,96 @@ fn templated(content: impl AsRef<str>) -> String {
         <meta charset="utf-8">
         <title>esp-rs web server</title>
     </head>
-    <h1>
-        {}
-    </h1>
+    {}
 </html>
 "#,
         content.as_ref()
     )
 }
 
-fn index_html(caudal: f32) -> String {
-    templated(format!("{} L/min", caudal))
+fn index_html(status: &StatusResponse) -> String {
+    let meters: String = status
+        .meters
+        .iter()
+        .map(|meter| {
+            format!(
+                r#"
+    <h2>{name}</h2>
+    <p>{} L/min</p>
+    <p>Total: {:.1} L</p>
+    <form method="post" action="/trip/reset">
+        <input type="hidden" name="meter" value="{name}">
+        Parcial: {:.1} L <input type="submit" value="Reiniciar">
+    </form>
+"#,
+                meter.flow,
+                meter.volume,
+                meter.trip_volume,
+                name = meter.name
+            )
+        })
+        .collect();
+    templated(format!(
+        r#"
+    <h1>
+        {} L/min
+    </h1>
+    <form method="post" action="/pump/ack">
+        Bomba: {:?} <input type="submit" value="Reconocer falla">
+    </form>
+    <form method="post" action="/pump/mode">
+        Modo: {:?}
+        <select name="mode">
+            <option value="auto">Automático</option>
+            <option value="force_on">Encendida</option>
+            <option value="force_off">Apagada</option>
+            <option value="timed_on">Encendida por</option>
+        </select>
+        <input name="minutes" value="10"> min
+        <input type="submit" value="Aplicar">
+    </form>
+    {}
+    <form method="post" action="/config">
+        <label>Umbral mínimo <input name="threshold_min" value="{}"> L/min</label>
+        <label>Umbral máximo <input name="threshold_max" value="{}"> L/min</label>
+        <input type="submit" value="Guardar">
+    </form>
+    <form method="post" action="/ota/check">
+        Firmware {} (disponible: {}) <input type="submit" value="Buscar actualización">
+    </form>
+    <form id="ota">
+        <label>Manifiesto <input type="file" name="manifest" accept=".json"></label>
+        <label>Imagen <input type="file" name="image" accept=".bin"></label>
+        <label>Token <input type="password" name="token"></label>
+        <input type="submit" value="Actualizar">
+        <span id="ota_result"></span>
+    </form>
+    <script>
+        document.getElementById("ota").onsubmit = async (event) => {{
+            event.preventDefault();
+            const form = event.target;
+            const result = document.getElementById("ota_result");
+            const manifest = JSON.stringify(JSON.parse(await form.manifest.files[0].text()));
+            result.textContent = "Subiendo...";
+            const response = await fetch("/api/v1/ota", {{
+                method: "POST",
+                headers: {{ "Authorization": "Bearer " + form.token.value }},
+                body: new Blob([manifest, "\n", form.image.files[0]]),
+            }});
+            const body = await response.json();
+            result.textContent = response.ok
+                ? "Instalada, reinicia cuando la bomba y el horario lo permitan"
+                : body.error;
+        }};
+    </script>
+"#,
+        status.flow,
+        status.pump.state,
+        status.pump.mode,
+        meters,
+        status.threshold_min,
+        status.threshold_max,
+        status.firmware_version,
+        status.ota.available.as_deref().unwrap_or("-")
+    ))
 }
@@ -0,0 +1,93 @@
+use anyhow::Result;
+use esp_idf_svc::nvs::{
+    EspCustomNvs, EspCustomNvsPartition, EspDefaultNvs, EspDefaultNvsPartition,
+};
+use log::warn;
+use serde::{de::DeserializeOwned, Serialize};
+use std::sync::{Arc, Mutex};
+
+const NAMESPACE: &str = "caudalimetro";
+const MAX_VALUE_LEN: usize = 1024;
+/// Written once at manufacturing, see `tools/label`.
+const FACTORY_PARTITION: &str = "factory";
+/// Label secrets are short, see `tools/label`.
+const MAX_FACTORY_LEN: usize = 64;
+
+/// Settings kept in the default NVS partition, one JSON blob per key.
+#[derive(Clone)]
+pub struct Storage {
+    nvs: Arc<Mutex<EspDefaultNvs>>,
+}
+
+impl Storage {
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<Self> {
+        Self::with_namespace(partition, NAMESPACE)
+    }
+
+    /// For modules that keep their settings apart from the application's.
+    pub fn with_namespace(partition: EspDefaultNvsPartition, namespace: &str) -> Result<Self> {
+        let nvs = EspDefaultNvs::new(partition, namespace, true)?;
+        Ok(Self {
+            nvs: Arc::new(Mutex::new(nvs)),
+        })
+    }
+
+    /// Returns `None` if the key is missing or its content can't be decoded.
+    pub fn load<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let nvs = self.nvs.lock().unwrap();
+        let mut buf = vec![0_u8; MAX_VALUE_LEN];
+        match nvs.get_raw(key, &mut buf) {
+            Ok(Some(raw)) => match serde_json::from_slice(raw) {
+                Ok(value) => Some(value),
+                Err(err) => {
+                    // The message can quote the stored value, which may be secret
+                    warn!(
+                        "Discarding malformed NVS value {}: {:?} error at column {}",
+                        key,
+                        err.classify(),
+                        err.column()
+                    );
+                    None
+                }
+            },
+            Ok(None) => None,
+            Err(err) => {
+                warn!("Failed to read NVS value {}: {}", key, err);
+                None
+            }
+        }
+    }
+
+    pub fn store<T: Serialize>(&self, key: &str, value: &T) -> Result<()> {
+        let raw = serde_json::to_vec(value)?;
+        self.nvs.lock().unwrap().set_raw(key, &raw)?;
+        Ok(())
+    }
+}
+
+/// Read-only per-device values from the `factory` partition. The settings
+/// wipe leaves it alone, so what is printed on the label keeps working.
+pub struct Factory {
+    nvs: EspCustomNvs,
+}
+
+impl Factory {
+    /// Fails on devices flashed before the partition existed.
+    pub fn open(namespace: &str) -> Result<Self> {
+        let partition = EspCustomNvsPartition::take(FACTORY_PARTITION)?;
+        Ok(Self {
+            nvs: EspCustomNvs::new(partition, namespace, false)?,
+        })
+    }
+
+    pub fn load(&self, key: &str) -> Option<String> {
+        let mut buf = [0_u8; MAX_FACTORY_LEN + 1];
+        match self.nvs.get_str(key, &mut buf) {
+            Ok(value) => value.map(|value| value.trim_end_matches('\0').to_owned()),
+            Err(err) => {
+                warn!("Failed to read factory value {}: {}", key, err);
+                None
+            }
+        }
+    }
+}
@@ -1,221 +1,198 @@
 use std::{
-    ffi::c_void,
-    mem::{size_of, size_of_val},
-    str,
+    sync::{mpsc, Arc, Mutex},
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use anyhow::Result;
-use embedded_svc::wifi::{AuthMethod, Configuration};
+use embedded_svc::wifi::Configuration;
 use esp_idf_hal::peripheral;
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop, nvs::EspDefaultNvsPartition, wifi::BlockingWifi, wifi::EspWifi,
 };
 use esp_idf_sys::*;
-
-static mut S_WIFI_EVENT_GROUP: *mut c_void = std::ptr::null_mut();
-
-static CONNECTED_BIT: u32 = BIT0;
-static ESPTOUCH_DONE_BIT: u32 = BIT1;
-
+use log::{info, warn};
+
+use crate::storage::{Factory, Storage};
+
+pub use networks::{Networks, SavedNetwork};
+pub use supervisor::{WifiState, WifiStatus};
+
+#[cfg(feature = "ble-provisioning")]
+mod ble;
+mod networks;
+mod portal;
+#[cfg(not(feature = "ble-provisioning"))]
+mod smartconfig;
+mod supervisor;
+
+#[cfg(feature = "ble-provisioning")]
+use ble::provision;
+#[cfg(not(feature = "ble-provisioning"))]
+use smartconfig::provision;
+use supervisor::{StaEvent, Supervisor};
+
+const NAMESPACE: &str = "wifi";
+/// Name the installer gave the device while provisioning.
+const DEVICE_NAME_KEY: &str = "device_name";
+/// How long saved or freshly entered credentials get to bring the link up.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long provisioning waits for the phone app before the portal takes over.
+const PROVISIONING_TIMEOUT: Duration = Duration::from_secs(120);
+const LINK_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// Scans, provisioning and the portal all run on the supervisor's thread.
+const TASK_STACK_SIZE: usize = 12 * 1024;
+
+/// The driver, kept by the supervisor once the first attempt is over.
+type Wifi = BlockingWifi<EspWifi<'static>>;
+
+/// Connects to the best saved network in range. Without saved networks it
+/// waits for provisioning and then for the portal. After that a
+/// [`Supervisor`] keeps the link up, also when the saved networks weren't
+/// in range at boot. `on_connected` runs every time the link comes up.
+/// Provisioning can set the OTA upload token in `app_storage`.
 pub fn wifi(
     modem: impl peripheral::Peripheral<P = esp_idf_hal::modem::Modem> + 'static,
     sysloop: EspSystemEventLoop,
     nvs_partition: EspDefaultNvsPartition,
-) -> Result<Box<EspWifi<'static>>> {
-    let mut _auth_method = AuthMethod::WPA2Personal;
-    unsafe {
-        if let Some(x_event_group_create) = g_wifi_osi_funcs._event_group_create {
-            S_WIFI_EVENT_GROUP = x_event_group_create();
+    app_storage: Storage,
+    on_connected: impl Fn() + Send + 'static,
+) -> Result<(Arc<Mutex<Networks>>, Arc<Mutex<WifiStatus>>)> {
+    let storage = Storage::with_namespace(nvs_partition.clone(), NAMESPACE)?;
+    let networks = Arc::new(Mutex::new(Networks::load(storage.clone())));
+    let status = Arc::new(Mutex::new(WifiStatus::default()));
+
+    let esp_wifi = EspWifi::new(modem, sysloop.clone(), Some(nvs_partition))?;
+
+    // Subscribed before connecting so no disconnect reason gets lost
+    let (events, received) = mpsc::channel();
+    let subscription = sysloop.subscribe(move |event: &StaEvent| {
+        let _ = events.send(*event);
+    })?;
+
+    let mut wifi = BlockingWifi::wrap(esp_wifi, sysloop.clone())?;
+
+    // Devices set up before the list existed only have the driver's config
+    remember(&wifi, &networks);
+
+    let connected = if networks.lock().unwrap().is_empty() {
+        // Nothing to retry, so this waits until someone configures it
+        if !provision(
+            &mut wifi,
+            &sysloop,
+            &storage,
+            &app_storage,
+            PROVISIONING_TIMEOUT,
+        )? {
+            wifi.stop()?;
+            portal::portal(&mut wifi, &app_storage, CONNECT_TIMEOUT, None)?;
         }
+        true
+    } else {
+        wifi.set_configuration(&Configuration::Client(Default::default()))?;
+        wifi.start()?;
+        connect_best(&mut wifi, &networks)?
+    };
+    status.lock().unwrap().device_name = storage.load(DEVICE_NAME_KEY);
+    // The supervisor reports the link as up on its first check
+    if connected {
+        remember(&wifi, &networks);
+    } else {
+        println!("Ninguna red guardada disponible, se reintenta en segundo plano");
     }
 
-    let mut esp_wifi = EspWifi::new(modem, sysloop.clone(), Some(nvs_partition))?;
-
-    let mut wifi = BlockingWifi::wrap(&mut esp_wifi, sysloop)?;
+    let supervisor = Supervisor {
+        wifi,
+        networks: networks.clone(),
+        status: status.clone(),
+        sysloop,
+        storage,
+        app_storage,
+        on_connected: Box::new(on_connected),
+    };
+    thread::Builder::new()
+        .name("wifi".to_owned())
+        .stack_size(TASK_STACK_SIZE)
+        .spawn(move || {
+            let _subscription = subscription;
+            supervisor.run(received)
+        })?;
+
+    Ok((networks, status))
+}
 
-    let config = wifi.get_configuration()?;
-    if let Configuration::Client(conf) = config {
-        if !conf.ssid.is_empty() {
-            println!("Conexion guardada {:?}", conf);
-            wifi.start()?;
-            wifi.connect()?;
-            while !wifi.is_up()? {
-                println!("Esperando a {:?}", conf);
-                thread::sleep(Duration::from_secs(1));
-            }
-            println!("Conectado a {}", conf.ssid);
-            return Ok(Box::new(esp_wifi));
-        }
+/// Adds the network the driver is configured for to the saved ones.
+fn remember(wifi: &Wifi, networks: &Mutex<Networks>) {
+    let Ok(Configuration::Client(conf)) = wifi.get_configuration() else {
+        return;
+    };
+    if conf.ssid.is_empty() {
+        return;
     }
-
-    unsafe {
-        esp!(esp_event_handler_register(
-            WIFI_EVENT,
-            ESP_EVENT_ANY_ID,
-            Some(event_handler),
-            std::ptr::null_mut(),
-        ))?;
-        esp!(esp_event_handler_register(
-            IP_EVENT,
-            ip_event_t_IP_EVENT_STA_GOT_IP as i32,
-            Some(event_handler),
-            std::ptr::null_mut(),
-        ))?;
-        esp!(esp_event_handler_register(
-            SC_EVENT,
-            ESP_EVENT_ANY_ID,
-            Some(event_handler),
-            std::ptr::null_mut(),
-        ))?;
+    let mut networks = networks.lock().unwrap();
+    let priority = networks
+        .list()
+        .iter()
+        .find(|saved| saved.ssid == conf.ssid.as_str())
+        .map_or(0, |saved| saved.priority);
+    let added = networks.add(SavedNetwork {
+        ssid: conf.ssid.to_string(),
+        password: conf.password.to_string(),
+        priority,
+    });
+    if let Err(err) = added {
+        warn!("Could not save network {}: {}", conf.ssid, err);
     }
+}
 
-    wifi.set_configuration(&Configuration::Client(Default::default()))?;
-
-    wifi.start()?;
+/// Tries the saved networks in range, best first.
+fn connect_best(wifi: &mut Wifi, networks: &Mutex<Networks>) -> Result<bool> {
+    let _ = wifi.disconnect();
+    let scan = wifi.scan()?;
+    let candidates = networks.lock().unwrap().candidates(&scan);
+    for network in candidates {
+        println!("Conectando a {}", network.ssid);
+        wifi.set_configuration(&Configuration::Client(network.client_configuration()))?;
+        let _ = wifi.connect();
+        if wait_up(wifi, CONNECT_TIMEOUT)? {
+            println!("Conectado a {}", network.ssid);
+            return Ok(true);
+        }
+        println!("No se pudo conectar a {}", network.ssid);
+        let _ = wifi.disconnect();
+    }
+    Ok(false)
+}
 
-    println!("Esperando smartconfig");
+/// Waits for an IP address, `false` if it didn't come within `timeout`.
+fn wait_up(wifi: &Wifi, timeout: Duration) -> Result<bool> {
+    let start = Instant::now();
     while !wifi.is_up()? {
+        if start.elapsed() >= timeout {
+            return Ok(false);
+        }
         thread::sleep(Duration::from_secs(1));
     }
-
-    Ok(Box::new(esp_wifi))
+    Ok(true)
 }
 
-unsafe extern "C" fn event_handler(
-    _event_handler_arg: *mut ::core::ffi::c_void,
-    event_base: esp_event_base_t,
-    event_id: i32,
-    event_data: *mut ::core::ffi::c_void,
-) {
-    if event_base == WIFI_EVENT && event_id == wifi_event_t_WIFI_EVENT_STA_START as i32 {
-        thread::spawn(|| smartconfig_example_task());
-    } else if event_base == WIFI_EVENT
-        && event_id == wifi_event_t_WIFI_EVENT_STA_DISCONNECTED as i32
-    {
-        esp_wifi_connect();
-        if let Some(x_event_group_clear_bits) = g_wifi_osi_funcs._event_group_clear_bits {
-            x_event_group_clear_bits(S_WIFI_EVENT_GROUP, CONNECTED_BIT);
-        }
-    } else if event_base == IP_EVENT && event_id == ip_event_t_IP_EVENT_STA_GOT_IP as i32 {
-        if let Some(x_event_group_set_bits) = g_wifi_osi_funcs._event_group_set_bits {
-            x_event_group_set_bits(S_WIFI_EVENT_GROUP, CONNECTED_BIT);
-        }
-    } else if event_base == SC_EVENT && event_id == smartconfig_event_t_SC_EVENT_SCAN_DONE as i32 {
-        println!("Scan done");
-    } else if event_base == SC_EVENT
-        && event_id == smartconfig_event_t_SC_EVENT_FOUND_CHANNEL as i32
-    {
-        println!("Found channel");
-    } else if event_base == SC_EVENT
-        && event_id == smartconfig_event_t_SC_EVENT_GOT_SSID_PSWD as i32
-    {
-        println!("Got SSID and password");
-
-        let evt: *mut smartconfig_event_got_ssid_pswd_t =
-            event_data as *mut smartconfig_event_got_ssid_pswd_t;
-        let mut wifi_config: wifi_config_t = Default::default();
-        let mut ssid: [u8; 33] = [0; 33];
-        let mut password: [u8; 65] = [0; 65];
-        let mut rvd_data: [u8; 33] = [0; 33];
-
-        bzero(
-            &mut wifi_config as *mut _ as *mut c_void,
-            size_of::<wifi_config_t>() as u32,
-        );
-        memcpy(
-            wifi_config.sta.ssid.as_mut_ptr() as *mut c_void,
-            (*evt).ssid.as_ptr() as *const c_void,
-            size_of_val(&wifi_config.sta.ssid) as u32,
-        );
-        memcpy(
-            wifi_config.sta.password.as_mut_ptr() as *mut c_void,
-            (*evt).password.as_ptr() as *const c_void,
-            size_of_val(&wifi_config.sta.password) as u32,
-        );
-        wifi_config.sta.bssid_set = (*evt).bssid_set;
-        if wifi_config.sta.bssid_set == true {
-            memcpy(
-                wifi_config.sta.bssid.as_mut_ptr() as *mut c_void,
-                (*evt).bssid.as_ptr() as *const c_void,
-                size_of_val(&wifi_config.sta.bssid) as u32,
-            );
-        }
-
-        memcpy(
-            ssid.as_mut_ptr() as *mut c_void,
-            (*evt).ssid.as_ptr() as *const c_void,
-            size_of_val(&(*evt).ssid) as u32,
-        );
-        memcpy(
-            password.as_mut_ptr() as *mut c_void,
-            (*evt).password.as_ptr() as *const c_void,
-            size_of_val(&(*evt).password) as u32,
-        );
-        let ssid_s = match str::from_utf8(&ssid) {
-            Ok(s) => s,
-            Err(_) => "utf error",
-        };
-        let password_s = match str::from_utf8(&password) {
-            Ok(s) => s,
-            Err(_) => "utf error",
-        };
-        println!("SSID:{}", ssid_s);
-        println!("PASSWORD:{}", password_s);
-        if (*evt).type_ == smartconfig_type_t_SC_TYPE_ESPTOUCH_V2 {
-            let _ = esp!(esp_smartconfig_get_rvd_data(
-                rvd_data.as_mut_ptr() as *mut u8,
-                size_of_val(&rvd_data) as u8
-            ));
-            println!("RVD_DATA:");
-            for i in 0..33 {
-                print!("{:02x} ", rvd_data[i]);
+/// A secret printed on the label, from the factory partition. Devices set
+/// up before it existed kept theirs in `storage`.
+fn label_secret(storage: &Storage, key: &str) -> Option<String> {
+    match Factory::open(NAMESPACE) {
+        Ok(factory) => {
+            if let Some(secret) = factory.load(key) {
+                return Some(secret);
             }
-            println!("");
-        }
-
-        let _ = esp!(esp_wifi_disconnect());
-        let _ = esp!(esp_wifi_set_config(
-            wifi_interface_t_WIFI_IF_STA,
-            &mut wifi_config as *mut wifi_config_t
-        ));
-        esp_wifi_connect();
-    } else if event_base == SC_EVENT
-        && event_id == smartconfig_event_t_SC_EVENT_SEND_ACK_DONE as i32
-    {
-        if let Some(x_event_group_set_bits) = g_wifi_osi_funcs._event_group_set_bits {
-            x_event_group_set_bits(S_WIFI_EVENT_GROUP, ESPTOUCH_DONE_BIT);
         }
+        Err(err) => info!("No factory partition: {}", err),
     }
+    storage.load(key)
 }
 
-fn smartconfig_example_task() {
-    unsafe {
-        let mut ux_bits = 0;
-        let _ = esp!(esp_smartconfig_set_type(
-            smartconfig_type_t_SC_TYPE_ESPTOUCH
-        ));
-        let cfg: smartconfig_start_config_t = Default::default();
-        let _ = esp!(esp_smartconfig_start(&cfg));
-        loop {
-            if let Some(x_event_group_wait_bits) = g_wifi_osi_funcs._event_group_wait_bits {
-                ux_bits = x_event_group_wait_bits(
-                    S_WIFI_EVENT_GROUP,
-                    CONNECTED_BIT | ESPTOUCH_DONE_BIT,
-                    true as i32,
-                    false as i32,
-                    u32::MAX,
-                );
-            }
-            if ux_bits & CONNECTED_BIT != 0 {
-                println!("WiFi Connected to ap");
-            }
-            if ux_bits & ESPTOUCH_DONE_BIT != 0 {
-                println!("smartconfig over");
-                esp_smartconfig_stop();
-                vTaskDelete(std::ptr::null_mut());
-            }
-        }
-    }
+/// Signal strength of the access point we are associated with, if any.
+pub fn rssi() -> Option<i8> {
+    let mut ap_info: wifi_ap_record_t = Default::default();
+    esp!(unsafe { esp_wifi_sta_get_ap_info(&mut ap_info) }).ok()?;
+    Some(ap_info.rssi)
 }
@@ -0,0 +1,145 @@
+//! ESP-IDF unified provisioning over BLE, for Espressif's open provisioning
+//! apps. Needs Bluetooth in the sdkconfig, see `sdkconfig.ble_provisioning`.
+//! The OTA upload token can come along on the `custom-data` endpoint, the
+//! one `esp_prov.py --custom_data` writes to.
+
+use std::{
+    ffi::{c_void, CString},
+    ptr, slice,
+    time::Duration,
+};
+
+use anyhow::Result;
+use embedded_svc::wifi::Configuration;
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_sys::*;
+use log::warn;
+
+use super::Wifi;
+use crate::{ota, storage::Storage};
+
+/// Proof of possession printed on the device label, see `tools/label`.
+const POP_KEY: &str = "pop";
+const TOKEN_ENDPOINT: &str = "custom-data";
+
+/// Advertises the provisioning service until the link is up, `false` if
+/// nothing arrived within `timeout`.
+pub fn provision(
+    wifi: &mut Wifi,
+    _sysloop: &EspSystemEventLoop,
+    storage: &Storage,
+    app_storage: &Storage,
+    timeout: Duration,
+) -> Result<bool> {
+    wifi.set_configuration(&Configuration::Client(Default::default()))?;
+    wifi.start()?;
+
+    // Without the label's secret nobody could connect, the portal takes over
+    let Some(pop) = super::label_secret(storage, POP_KEY) else {
+        warn!("No BLE proof of possession on this device, skipping BLE provisioning");
+        return Ok(false);
+    };
+    let pop = CString::new(pop)?;
+    let service_name = service_name()?;
+    let service = CString::new(service_name.as_str())?;
+
+    let config = wifi_prov_mgr_config_t {
+        scheme: unsafe { wifi_prov_scheme_ble },
+        // Only the unused classic Bluetooth memory goes back to the heap. BLE
+        // has to stay, the supervisor provisions again once the saved
+        // networks are gone and a released controller can't start anymore.
+        scheme_event_handler: wifi_prov_event_handler_t {
+            event_cb: Some(wifi_prov_scheme_ble_event_cb_free_bt),
+            user_data: ptr::null_mut(),
+        },
+        app_event_handler: wifi_prov_event_handler_t {
+            event_cb: None,
+            user_data: ptr::null_mut(),
+        },
+    };
+    esp!(unsafe { wifi_prov_mgr_init(config) })?;
+
+    let endpoint = CString::new(TOKEN_ENDPOINT)?;
+    let started = esp!(unsafe { wifi_prov_mgr_endpoint_create(endpoint.as_ptr()) })
+        .and_then(|()| {
+            esp!(unsafe {
+                wifi_prov_mgr_start_provisioning(
+                    wifi_prov_security_WIFI_PROV_SECURITY_1,
+                    pop.as_ptr(),
+                    service.as_ptr(),
+                    ptr::null(),
+                )
+            })
+        })
+        .and_then(|()| {
+            // The storage outlives the endpoint, deinit below removes it
+            esp!(unsafe {
+                wifi_prov_mgr_endpoint_register(
+                    endpoint.as_ptr(),
+                    Some(receive_token),
+                    app_storage as *const Storage as *mut c_void,
+                )
+            })
+        });
+    if let Err(err) = started {
+        unsafe { wifi_prov_mgr_deinit() };
+        return Err(err.into());
+    }
+
+    println!("Esperando provisionamiento BLE como {}", service_name);
+    let connected = super::wait_up(wifi, timeout)?;
+    if connected {
+        // The manager stops on its own once the app is done
+        unsafe { wifi_prov_mgr_wait() };
+    } else {
+        println!("Provisionamiento BLE sin respuesta");
+    }
+    unsafe { wifi_prov_mgr_deinit() };
+    Ok(connected)
+}
+
+/// Saves the OTA upload token sent on [`TOKEN_ENDPOINT`], encrypted by the
+/// provisioning session like the credentials.
+unsafe extern "C" fn receive_token(
+    _session_id: u32,
+    inbuf: *const u8,
+    inlen: ssize_t,
+    outbuf: *mut *mut u8,
+    outlen: *mut ssize_t,
+    priv_data: *mut c_void,
+) -> esp_err_t {
+    let storage = &*(priv_data as *const Storage);
+    let received = if inbuf.is_null() || inlen <= 0 {
+        &[][..]
+    } else {
+        slice::from_raw_parts(inbuf, inlen as usize)
+    };
+    let saved = core::str::from_utf8(received)
+        .map_err(anyhow::Error::from)
+        .and_then(|token| ota::provision_upload_token(storage, token.trim()));
+    let answer: &[u8] = match saved {
+        Ok(()) => {
+            println!("Token de actualizacion guardado");
+            b"ok"
+        }
+        Err(err) => {
+            warn!("Could not save the upload token: {}", err);
+            b"error"
+        }
+    };
+    // Protocomm frees the answer
+    let out = heap_caps_malloc(answer.len(), MALLOC_CAP_DEFAULT) as *mut u8;
+    if out.is_null() {
+        return ESP_ERR_NO_MEM as esp_err_t;
+    }
+    ptr::copy_nonoverlapping(answer.as_ptr(), out, answer.len());
+    *outbuf = out;
+    *outlen = answer.len() as ssize_t;
+    ESP_OK as esp_err_t
+}
+
+fn service_name() -> Result<String> {
+    let mut mac = [0_u8; 6];
+    esp!(unsafe { esp_wifi_get_mac(wifi_interface_t_WIFI_IF_STA, mac.as_mut_ptr()) })?;
+    Ok(format!("PROV_{:02X}{:02X}{:02X}", mac[3], mac[4], mac[5]))
+}
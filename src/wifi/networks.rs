@@ -0,0 +1,151 @@
+use std::fmt;
+
+use anyhow::{bail, Result};
+use embedded_svc::wifi::{AccessPointInfo, AuthMethod, ClientConfiguration};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::storage::Storage;
+
+const NETWORKS_KEY: &str = "networks";
+pub const MAX_NETWORKS: usize = 5;
+const MAX_SSID_LEN: usize = 32;
+const MAX_PASSWORD_LEN: usize = 64;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct SavedNetwork {
+    pub ssid: String,
+    pub password: String,
+    /// Higher wins among the networks in range.
+    #[serde(default)]
+    pub priority: u8,
+}
+
+// Keeps the password out of the logs
+impl fmt::Debug for SavedNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SavedNetwork")
+            .field("ssid", &self.ssid)
+            .field("password", &"<redacted>")
+            .field("priority", &self.priority)
+            .finish()
+    }
+}
+
+impl SavedNetwork {
+    pub fn is_valid(&self) -> bool {
+        !self.ssid.is_empty()
+            && self.ssid.len() <= MAX_SSID_LEN
+            && self.password.len() <= MAX_PASSWORD_LEN
+    }
+
+    pub fn client_configuration(&self) -> ClientConfiguration {
+        ClientConfiguration {
+            ssid: self.ssid.as_str().into(),
+            password: self.password.as_str().into(),
+            auth_method: if self.password.is_empty() {
+                AuthMethod::None
+            } else {
+                AuthMethod::WPA2Personal
+            },
+            ..Default::default()
+        }
+    }
+}
+
+/// Known networks, kept in the Wi-Fi NVS namespace.
+pub struct Networks {
+    list: Vec<SavedNetwork>,
+    storage: Storage,
+}
+
+impl Networks {
+    pub fn load(storage: Storage) -> Self {
+        let mut list: Vec<SavedNetwork> = storage.load(NETWORKS_KEY).unwrap_or_default();
+        let count = list.len();
+        list.retain(SavedNetwork::is_valid);
+        list.truncate(MAX_NETWORKS);
+        if list.len() != count {
+            warn!("Dropped {} invalid saved networks", count - list.len());
+        }
+        Self { list, storage }
+    }
+
+    pub fn list(&self) -> &[SavedNetwork] {
+        &self.list
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    /// Replaces the entry with the same SSID. A new network only pushes out
+    /// one with a lower priority once the list is full.
+    pub fn add(&mut self, network: SavedNetwork) -> Result<()> {
+        if !network.is_valid() {
+            bail!(
+                "SSID must be 1 to {} bytes and the password up to {}",
+                MAX_SSID_LEN,
+                MAX_PASSWORD_LEN
+            );
+        }
+        if let Some(saved) = self
+            .list
+            .iter_mut()
+            .find(|saved| saved.ssid == network.ssid)
+        {
+            if *saved == network {
+                return Ok(());
+            }
+            *saved = network;
+        } else if self.list.len() < MAX_NETWORKS {
+            self.list.push(network);
+        } else {
+            let Some(lowest) = self
+                .list
+                .iter_mut()
+                .filter(|saved| saved.priority < network.priority)
+                .min_by_key(|saved| saved.priority)
+            else {
+                bail!("Already {} saved networks", MAX_NETWORKS);
+            };
+            *lowest = network;
+        }
+        self.save()
+    }
+
+    /// Returns `false` if there was no network with that SSID.
+    pub fn remove(&mut self, ssid: &str) -> Result<bool> {
+        let count = self.list.len();
+        self.list.retain(|saved| saved.ssid != ssid);
+        if self.list.len() == count {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Saved networks in range, best first: highest priority, then the
+    /// strongest signal.
+    pub fn candidates(&self, scan: &[AccessPointInfo]) -> Vec<SavedNetwork> {
+        let mut found: Vec<(&SavedNetwork, i8)> = self
+            .list
+            .iter()
+            .filter_map(|saved| {
+                scan.iter()
+                    .filter(|ap| ap.ssid.as_str() == saved.ssid)
+                    .map(|ap| ap.signal_strength)
+                    .max()
+                    .map(|rssi| (saved, rssi))
+            })
+            .collect();
+        found.sort_by(|(a, a_rssi), (b, b_rssi)| {
+            b.priority.cmp(&a.priority).then(b_rssi.cmp(a_rssi))
+        });
+        found.into_iter().map(|(saved, _)| saved.clone()).collect()
+    }
+
+    fn save(&self) -> Result<()> {
+        self.storage.store(NETWORKS_KEY, &self.list)
+    }
+}
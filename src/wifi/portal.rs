@@ -0,0 +1,399 @@
+//! SoftAP fallback with a captive portal to enter the Wi-Fi credentials
+//! from any phone or laptop.
+
+use std::{
+    net::{Ipv4Addr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use embedded_svc::{
+    http::{Headers, Method},
+    io::{Read, Write},
+    wifi::{AccessPointConfiguration, AuthMethod, ClientConfiguration, Configuration},
+};
+use esp_idf_svc::http::server::{Configuration as HttpConfiguration, EspHttpServer};
+use log::{info, warn};
+
+use super::Wifi;
+use crate::{ota, storage::Storage};
+
+const AP_SSID: &str = "caudalimetro";
+const DNS_PORT: u16 = 53;
+/// The app server lets port 80 go once the supervisor reports the portal,
+/// see `run`. Attempts to take it over, one second apart.
+const BIND_ATTEMPTS: u32 = 5;
+/// The app server keeps the default 32768.
+const CTRL_PORT: u16 = 32769;
+const MAX_SSID_LEN: usize = 32;
+const MAX_PASSWORD_LEN: usize = 64;
+/// `ssid=`, `&password=` and `&token=` with every byte percent-encoded,
+/// longer bodies can't be valid.
+const MAX_FORM_LEN: usize =
+    5 + 3 * MAX_SSID_LEN + 10 + 3 * MAX_PASSWORD_LEN + 7 + 3 * ota::MAX_TOKEN_LEN;
+
+struct Credentials {
+    ssid: String,
+    password: String,
+    /// OTA upload token, saved once the credentials connect.
+    token: Option<String>,
+}
+
+/// Serves the portal until the credentials entered in it connect within `timeout`, then saves them in the driver's NVS config and
+/// drops the AP. With a `deadline` it gives up once nothing connected in
+/// that time and returns `false`. The AP is dropped on errors too.
+pub fn portal(
+    wifi: &mut Wifi,
+    app_storage: &Storage,
+    timeout: Duration,
+    deadline: Option<Duration>,
+) -> Result<bool> {
+    let ap = AccessPointConfiguration {
+        ssid: AP_SSID.into(),
+        auth_method: AuthMethod::None,
+        ..Default::default()
+    };
+    wifi.set_configuration(&Configuration::Mixed(
+        ClientConfiguration::default(),
+        ap.clone(),
+    ))?;
+
+    let client = match serve(wifi, app_storage, &ap, timeout, deadline) {
+        Ok(Some(client)) => client,
+        Ok(None) => {
+            println!("Portal de configuracion cerrado sin cambios");
+            wifi.set_configuration(&Configuration::Client(Default::default()))?;
+            return Ok(false);
+        }
+        Err(err) => {
+            let _ = wifi.set_configuration(&Configuration::Client(Default::default()));
+            return Err(err);
+        }
+    };
+
+    // Station only from now on, this is also what gets saved to NVS
+    wifi.set_configuration(&Configuration::Client(client.clone()))?;
+    if !wifi.is_up()? {
+        let _ = wifi.connect();
+        super::wait_up(wifi, timeout)?;
+    }
+    println!("Conectado a {}", client.ssid);
+    Ok(true)
+}
+
+/// Runs the AP, DNS and HTTP server until some credentials connect. Both
+/// servers are gone when it returns, whichever way.
+fn serve(
+    wifi: &mut Wifi,
+    app_storage: &Storage,
+    ap: &AccessPointConfiguration,
+    timeout: Duration,
+    deadline: Option<Duration>,
+) -> Result<Option<ClientConfiguration>> {
+    wifi.start()?;
+
+    let networks = Arc::new(Mutex::new(scan(wifi)));
+    let ip = wifi.wifi().ap_netif().get_ip_info()?.ip;
+    println!("Portal de configuracion en la red {} ({})", AP_SSID, ip);
+
+    let _dns = Dns::spawn(ip)?;
+    let (credentials, received) = mpsc::channel();
+    let _server = begin(networks.clone(), credentials)?;
+
+    let deadline = deadline.map(|deadline| Instant::now() + deadline);
+    loop {
+        let credentials = match deadline {
+            Some(deadline) => {
+                match received.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(credentials) => credentials,
+                    Err(RecvTimeoutError::Timeout) => return Ok(None),
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            None => received.recv()?,
+        };
+        let Credentials {
+            ssid,
+            password,
+            token,
+        } = credentials;
+        info!("Trying network {}", ssid);
+        let client = ClientConfiguration {
+            ssid: ssid.as_str().into(),
+            password: password.as_str().into(),
+            auth_method: if password.is_empty() {
+                AuthMethod::None
+            } else {
+                AuthMethod::WPA2Personal
+            },
+            ..Default::default()
+        };
+        wifi.set_configuration(&Configuration::Mixed(client.clone(), ap.clone()))?;
+        let _ = wifi.connect();
+        if super::wait_up(wifi, timeout)? {
+            if let Some(token) = token {
+                match ota::provision_upload_token(app_storage, &token) {
+                    Ok(()) => println!("Token de actualizacion guardado"),
+                    Err(err) => warn!("Could not save the upload token: {}", err),
+                }
+            }
+            return Ok(Some(client));
+        }
+        warn!("Could not connect to {}", ssid);
+        let _ = wifi.disconnect();
+        *networks.lock().unwrap() = scan(wifi);
+    }
+}
+
+/// SSIDs in range, strongest first.
+fn scan(wifi: &mut Wifi) -> Vec<String> {
+    let mut found = match wifi.scan() {
+        Ok(found) => found,
+        Err(err) => {
+            warn!("Wi-Fi scan failed: {}", err);
+            return Vec::new();
+        }
+    };
+    found.sort_by(|a, b| b.signal_strength.cmp(&a.signal_strength));
+    let mut networks: Vec<String> = Vec::new();
+    for ap in found {
+        if !ap.ssid.is_empty() && !networks.iter().any(|ssid| ssid == ap.ssid.as_str()) {
+            networks.push(ap.ssid.to_string());
+        }
+    }
+    networks
+}
+
+fn begin(
+    networks: Arc<Mutex<Vec<String>>>,
+    credentials: Sender<Credentials>,
+) -> Result<EspHttpServer> {
+    let configuration = HttpConfiguration {
+        ctrl_port: CTRL_PORT,
+        uri_match_wildcard: true,
+        ..Default::default()
+    };
+    let mut attempts = 1;
+    let mut server = loop {
+        match EspHttpServer::new(&configuration) {
+            Ok(server) => break server,
+            Err(err) if attempts < BIND_ATTEMPTS => {
+                info!("Portal port still taken ({}), retrying", err);
+                attempts += 1;
+                thread::sleep(Duration::from_secs(1));
+            }
+            Err(err) => return Err(err.into()),
+        }
+    };
+
+    server.fn_handler("/", Method::Get, move |request| {
+        let html = portal_html(&networks.lock().unwrap());
+        let mut response = request.into_ok_response()?;
+        response.write_all(html.as_bytes())?;
+        Ok(())
+    })?;
+
+    let credentials = Mutex::new(credentials);
+    server.fn_handler("/connect", Method::Post, move |mut request| {
+        if request.content_len().unwrap_or(0) > MAX_FORM_LEN as u64 {
+            request.into_status_response(413)?;
+            return Ok(());
+        }
+        // One byte more than allowed, so a body without length still shows
+        // when it is too long
+        let mut buf = [0_u8; MAX_FORM_LEN + 1];
+        let mut len = 0;
+        while len < buf.len() {
+            let read = request.read(&mut buf[len..])?;
+            if read == 0 {
+                break;
+            }
+            len += read;
+        }
+        if len > MAX_FORM_LEN {
+            request.into_status_response(413)?;
+            return Ok(());
+        }
+        let body = core::str::from_utf8(&buf[..len]).unwrap_or("");
+        let ssid = form_value(body, "ssid").unwrap_or_default();
+        let password = form_value(body, "password").unwrap_or_default();
+        let token = form_value(body, "token").filter(|token| !token.is_empty());
+        if ssid.is_empty()
+            || ssid.len() > MAX_SSID_LEN
+            || password.len() > MAX_PASSWORD_LEN
+            || token
+                .as_deref()
+                .map_or(false, |token| ota::check_upload_token(token).is_err())
+        {
+            request.into_status_response(400)?;
+            return Ok(());
+        }
+        let html = format!(
+            "<p>Conectando a {}... Si no lo logra este portal vuelve a aparecer.</p>",
+            escape(&ssid)
+        );
+        credentials.lock().unwrap().send(Credentials {
+            ssid,
+            password,
+            token,
+        })?;
+        let mut response = request.into_ok_response()?;
+        response.write_all(templated(html).as_bytes())?;
+        Ok(())
+    })?;
+
+    // Connectivity checks of phones and laptops land here and open the portal
+    server.fn_handler("/*", Method::Get, |request| {
+        request.into_response(302, Some("Found"), &[("Location", "/")])?;
+        Ok(())
+    })?;
+
+    Ok(server)
+}
+
+/// Answers every DNS query with our own address, so any name resolves to
+/// the portal. Stops when dropped.
+struct Dns {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Dns {
+    fn spawn(ip: Ipv4Addr) -> Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DNS_PORT))?;
+        socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = thread::spawn(move || {
+            let mut buf = [0_u8; 512];
+            while !stopped.load(Ordering::Relaxed) {
+                let Ok((len, from)) = socket.recv_from(&mut buf) else {
+                    continue;
+                };
+                if let Some(answer) = dns_answer(&buf[..len], ip) {
+                    let _ = socket.send_to(&answer, from);
+                }
+            }
+        });
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Dns {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn dns_answer(query: &[u8], ip: Ipv4Addr) -> Option<Vec<u8>> {
+    // Header, then the first question: labels up to a zero byte, type and class
+    let question_count = u16::from_be_bytes([*query.get(4)?, *query.get(5)?]);
+    if question_count == 0 {
+        return None;
+    }
+    let mut end = 12;
+    loop {
+        let label = *query.get(end)? as usize;
+        end += 1 + label;
+        if label == 0 {
+            break;
+        }
+    }
+    end += 4;
+    let question = query.get(12..end)?;
+
+    let mut answer = Vec::with_capacity(end + 16);
+    answer.extend_from_slice(&query[..2]);
+    // Standard response, recursion available, one question and one answer
+    answer.extend_from_slice(&[0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0]);
+    answer.extend_from_slice(question);
+    // Pointer to the name in the question, type A, class IN, TTL 60 s
+    answer.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+    answer.extend_from_slice(&ip.octets());
+    Some(answer)
+}
+
+fn form_value(body: &str, key: &str) -> Option<String> {
+    body.split('&').find_map(|pair| {
+        let (name, value) = pair.split_once('=')?;
+        (name == key).then(|| url_decode(value))
+    })
+}
+
+fn url_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            // `from_str_radix` alone would also take a sign, as in `%+1`
+            b'%' if i + 2 < bytes.len()
+                && bytes[i + 1].is_ascii_hexdigit()
+                && bytes[i + 2].is_ascii_hexdigit() =>
+            {
+                let hex = core::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+                decoded.push(u8::from_str_radix(hex, 16).unwrap_or(b'%'));
+                i += 2;
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn templated(content: impl AsRef<str>) -> String {
+    format!(
+        r#"
+<!DOCTYPE html>
+<html>
+    <head>
+        <meta charset="utf-8">
+        <meta name="viewport" content="width=device-width">
+        <title>Caudalimetro</title>
+    </head>
+    {}
+</html>
+"#,
+        content.as_ref()
+    )
+}
+
+fn portal_html(networks: &[String]) -> String {
+    let options: String = networks
+        .iter()
+        .map(|ssid| format!(r#"<option value="{0}">{0}</option>"#, escape(ssid)))
+        .collect();
+    templated(format!(
+        r#"
+    <h1>Configurar Wi-Fi</h1>
+    <form method="post" action="/connect">
+        <label>Red <select name="ssid">{}</select></label>
+        <label>Contraseña <input type="password" name="password"></label>
+        <label>Token de actualizacion (opcional) <input type="password" name="token"></label>
+        <input type="submit" value="Conectar">
+    </form>
+"#,
+        options
+    ))
+}
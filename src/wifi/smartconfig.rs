@@ -0,0 +1,330 @@
+//! ESP-Touch SmartConfig, needs Espressif's phone app. It listens for v2,
+//! where the credentials travel encrypted with a per-device key and the
+//! app's reserved data can carry the device name and an OTA upload token,
+//! and for v1 so older apps keep working. The key comes from the label,
+//! see `tools/label`.
+
+use std::{
+    ffi::{c_char, CString},
+    fmt, ptr,
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use embedded_svc::wifi::{AuthMethod, ClientConfiguration, Configuration};
+use esp_idf_svc::eventloop::{
+    EspEventFetchData, EspSystemEventLoop, EspTypedEventDeserializer, EspTypedEventSource,
+};
+use esp_idf_sys::*;
+use log::warn;
+
+use super::Wifi;
+use crate::{ota, storage::Storage};
+
+/// How long the phone app gets to hear back once the link is up.
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Until the app shows up, listening switches between v2 and v1 this often.
+const PROTOCOL_SLICE: Duration = Duration::from_secs(20);
+/// AES key the app needs to encrypt the credentials for this device, on
+/// the label.
+const KEY_KEY: &str = "touch_key";
+/// The AES-128 key length the app expects.
+const KEY_LEN: usize = 16;
+/// ESP-Touch v2 carries up to 64 bytes of reserved data.
+const MAX_RESERVED_LEN: usize = 64;
+
+#[derive(Clone)]
+struct Credentials {
+    ssid: String,
+    password: String,
+    bssid: Option<[u8; 6]>,
+    esptouch_v2: bool,
+}
+
+// Keeps the password out of the logs
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("ssid", &self.ssid)
+            .field("password", &"<redacted>")
+            .field("bssid", &self.bssid)
+            .field("esptouch_v2", &self.esptouch_v2)
+            .finish()
+    }
+}
+
+/// What the installer typed as reserved data in the app, either
+/// `name=<device name>;token=<OTA upload token>` or just the name.
+#[derive(Default, Debug)]
+struct ReservedData {
+    name: Option<String>,
+    token: Option<String>,
+}
+
+impl ReservedData {
+    fn parse(text: &str) -> Self {
+        let text = text.trim();
+        if !text.contains('=') {
+            return Self {
+                name: (!text.is_empty()).then(|| text.to_owned()),
+                token: None,
+            };
+        }
+        let mut data = Self::default();
+        for pair in text.split(';') {
+            let Some((key, value)) = pair.split_once('=') else {
+                continue;
+            };
+            let value = value.trim();
+            if value.is_empty() {
+                continue;
+            }
+            match key.trim() {
+                "name" => data.name = Some(value.to_owned()),
+                "token" => data.token = Some(value.to_owned()),
+                _ => {}
+            }
+        }
+        data
+    }
+
+    fn save(&self, storage: &Storage, app_storage: &Storage) -> Result<()> {
+        if let Some(name) = &self.name {
+            storage.store(super::DEVICE_NAME_KEY, name)?;
+            println!("Nombre del equipo: {}", name);
+        }
+        if let Some(token) = &self.token {
+            ota::provision_upload_token(app_storage, token)?;
+            println!("Token de actualizacion guardado");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+enum ScEvent {
+    ScanDone,
+    FoundChannel,
+    GotCredentials(Credentials),
+    AckDone,
+    Other,
+}
+
+impl EspTypedEventSource for ScEvent {
+    fn source() -> *const c_char {
+        unsafe { SC_EVENT }
+    }
+}
+
+impl EspTypedEventDeserializer<ScEvent> for ScEvent {
+    fn deserialize<R>(data: &EspEventFetchData, f: &mut impl for<'a> FnMut(&'a ScEvent) -> R) -> R {
+        let event = match data.event_id as u32 {
+            smartconfig_event_t_SC_EVENT_SCAN_DONE => ScEvent::ScanDone,
+            smartconfig_event_t_SC_EVENT_FOUND_CHANNEL => ScEvent::FoundChannel,
+            smartconfig_event_t_SC_EVENT_GOT_SSID_PSWD => {
+                let got =
+                    unsafe { (data.payload as *const smartconfig_event_got_ssid_pswd_t).as_ref() };
+                got.map_or(ScEvent::Other, |got| {
+                    ScEvent::GotCredentials(Credentials {
+                        ssid: c_string(&got.ssid),
+                        password: c_string(&got.password),
+                        bssid: got.bssid_set.then_some(got.bssid),
+                        esptouch_v2: got.type_ == smartconfig_type_t_SC_TYPE_ESPTOUCH_V2,
+                    })
+                })
+            }
+            smartconfig_event_t_SC_EVENT_SEND_ACK_DONE => ScEvent::AckDone,
+            _ => ScEvent::Other,
+        };
+        f(&event)
+    }
+}
+
+/// Runs SmartConfig until the link is up, `false` if nothing arrived
+/// within `timeout`.
+pub fn provision(
+    wifi: &mut Wifi,
+    sysloop: &EspSystemEventLoop,
+    storage: &Storage,
+    app_storage: &Storage,
+    timeout: Duration,
+) -> Result<bool> {
+    let (events, received) = mpsc::channel();
+    let _subscription = sysloop.subscribe(move |event: &ScEvent| {
+        let _ = events.send(event.clone());
+    })?;
+
+    wifi.set_configuration(&Configuration::Client(Default::default()))?;
+    wifi.start()?;
+
+    let key = encryption_key(storage).map(CString::new).transpose()?;
+    if key.is_none() {
+        warn!("No ESP-Touch key on this device, listening for v1 only");
+    }
+
+    println!("Esperando smartconfig");
+    let connected = wait_for_credentials(
+        wifi,
+        storage,
+        app_storage,
+        key.as_ref(),
+        &received,
+        Instant::now() + timeout,
+    );
+    // Dropping the subscription too, so nothing reconnects behind the
+    // supervisor's back
+    unsafe { esp_smartconfig_stop() };
+    let connected = connected?;
+    if !connected {
+        println!("Smartconfig sin respuesta");
+    }
+    Ok(connected)
+}
+
+/// Listens for ESP-Touch v2 with `key`, or without one for v1, which
+/// isn't encrypted.
+fn start(key: Option<&CString>) -> Result<()> {
+    let sc_type = if key.is_some() {
+        smartconfig_type_t_SC_TYPE_ESPTOUCH_V2
+    } else {
+        smartconfig_type_t_SC_TYPE_ESPTOUCH
+    };
+    esp!(unsafe { esp_smartconfig_set_type(sc_type) })?;
+    let config = smartconfig_start_config_t {
+        esp_touch_v2_enable_crypt: key.is_some(),
+        esp_touch_v2_key: key.map_or(ptr::null_mut(), |key| key.as_ptr() as *mut c_char),
+        ..Default::default()
+    };
+    esp!(unsafe { esp_smartconfig_start(&config) })?;
+    Ok(())
+}
+
+/// The label's key, `None` on devices without one.
+fn encryption_key(storage: &Storage) -> Option<String> {
+    let key = super::label_secret(storage, KEY_KEY)?;
+    if key.len() != KEY_LEN {
+        warn!("ESP-Touch key of the wrong length, ignoring it");
+        return None;
+    }
+    Some(key)
+}
+
+/// Connects with every set of credentials the app sends until one brings
+/// the link up, then lets the app know.
+fn wait_for_credentials(
+    wifi: &mut Wifi,
+    storage: &Storage,
+    app_storage: &Storage,
+    key: Option<&CString>,
+    events: &Receiver<ScEvent>,
+    deadline: Instant,
+) -> Result<bool> {
+    let mut v2 = key.is_some();
+    // Once an app was heard only its protocol is listened for, and without
+    // a key there is nothing to switch to
+    let mut switch_at = key.map(|_| Instant::now() + PROTOCOL_SLICE);
+    start(key.filter(|_| v2))?;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(false);
+        }
+        let until = switch_at.map_or(deadline, |switch_at| switch_at.min(deadline));
+        let event = match events.recv_timeout(until.saturating_duration_since(now)) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => {
+                if switch_at.is_some() {
+                    v2 = !v2;
+                    switch_at = Some(Instant::now() + PROTOCOL_SLICE);
+                    unsafe { esp_smartconfig_stop() };
+                    start(key.filter(|_| v2))?;
+                }
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let credentials = match event {
+            ScEvent::ScanDone => {
+                println!("Scan done");
+                continue;
+            }
+            ScEvent::FoundChannel => {
+                println!("Found channel");
+                switch_at = None;
+                continue;
+            }
+            ScEvent::AckDone | ScEvent::Other => continue,
+            ScEvent::GotCredentials(credentials) => credentials,
+        };
+
+        println!("Got SSID and password");
+        println!("SSID:{}", credentials.ssid);
+        if credentials.esptouch_v2 {
+            let mut reserved = [0_u8; MAX_RESERVED_LEN];
+            let read = esp!(unsafe {
+                esp_smartconfig_get_rvd_data(reserved.as_mut_ptr(), reserved.len() as u8)
+            });
+            match read {
+                Ok(()) => {
+                    let data = ReservedData::parse(&c_string(&reserved));
+                    if let Err(err) = data.save(storage, app_storage) {
+                        warn!("Could not save the reserved data: {}", err);
+                    }
+                }
+                Err(err) => warn!("Could not read the reserved data: {}", err),
+            }
+        }
+
+        let _ = wifi.disconnect();
+        // Also saved in the driver's NVS config
+        wifi.set_configuration(&Configuration::Client(client_configuration(&credentials)))?;
+        let _ = wifi.connect();
+        if super::wait_up(wifi, super::CONNECT_TIMEOUT)? {
+            println!("WiFi Connected to ap");
+            wait_for_ack(events);
+            println!("smartconfig over");
+            return Ok(true);
+        }
+
+        // Listen again so the app can retry, maybe with another password
+        let _ = wifi.disconnect();
+        unsafe { esp_smartconfig_stop() };
+        switch_at = key.map(|_| Instant::now() + PROTOCOL_SLICE);
+        start(key.filter(|_| v2))?;
+    }
+}
+
+fn wait_for_ack(events: &Receiver<ScEvent>) {
+    let deadline = Instant::now() + ACK_TIMEOUT;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match events.recv_timeout(remaining) {
+            Ok(ScEvent::AckDone) | Err(_) => return,
+            Ok(_) => {}
+        }
+    }
+}
+
+fn client_configuration(credentials: &Credentials) -> ClientConfiguration {
+    ClientConfiguration {
+        ssid: credentials.ssid.as_str().into(),
+        password: credentials.password.as_str().into(),
+        bssid: credentials.bssid,
+        auth_method: if credentials.password.is_empty() {
+            AuthMethod::None
+        } else {
+            AuthMethod::WPA2Personal
+        },
+        ..Default::default()
+    }
+}
+
+/// Text up to the first NUL of a fixed size C buffer.
+fn c_string(bytes: &[u8]) -> String {
+    let len = bytes
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
@@ -0,0 +1,291 @@
+//! Keeps the station link up: reconnects with exponential backoff, records
+//! why the link dropped and only opens the portal as a last resort.
+
+use std::{
+    ffi::c_char,
+    sync::{mpsc::Receiver, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use embedded_svc::wifi::Configuration;
+use esp_idf_hal::reset;
+use esp_idf_svc::eventloop::{
+    EspEventFetchData, EspSystemEventLoop, EspTypedEventDeserializer, EspTypedEventSource,
+};
+use esp_idf_sys::*;
+use log::{info, warn};
+use serde::Serialize;
+
+use super::{Networks, Wifi};
+use crate::storage::Storage;
+
+const RECONNECT_BASE: Duration = Duration::from_secs(2);
+const RECONNECT_MAX: Duration = Duration::from_secs(300);
+/// Failed reconnects, about a minute of backoff, before the portal opens.
+/// It opens again after as many more.
+const PORTAL_AFTER_ATTEMPTS: u32 = 5;
+/// How long the portal stays up before going back to the saved networks.
+const PORTAL_TIMEOUT: Duration = Duration::from_secs(600);
+/// Consecutive driver errors after which only a reboot can help.
+const MAX_DRIVER_ERRORS: u32 = 5;
+
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum WifiState {
+    #[default]
+    Connecting,
+    Connected,
+    /// Backing off before the next reconnect.
+    Waiting,
+    Provisioning,
+    Portal,
+}
+
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct Disconnect {
+    pub reason: u8,
+    pub name: &'static str,
+    /// Seconds since boot.
+    pub at: u64,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct WifiStatus {
+    pub state: WifiState,
+    pub ssid: Option<String>,
+    pub rssi: Option<i8>,
+    pub disconnects: u32,
+    pub last_disconnect: Option<Disconnect>,
+    /// Failed reconnects since the link was last up.
+    pub reconnect_attempts: u32,
+    /// Seconds until the next reconnect while waiting.
+    pub retry_in: Option<u64>,
+    /// Name the installer gave the device while provisioning.
+    pub device_name: Option<String>,
+}
+
+/// Station events the supervisor needs, with the disconnect reason the
+/// typed `WifiEvent` leaves out.
+#[derive(Clone, Copy, Debug)]
+pub enum StaEvent {
+    Connected,
+    Disconnected(u8),
+    Other,
+}
+
+impl EspTypedEventSource for StaEvent {
+    fn source() -> *const c_char {
+        unsafe { WIFI_EVENT }
+    }
+}
+
+impl EspTypedEventDeserializer<StaEvent> for StaEvent {
+    fn deserialize<R>(
+        data: &EspEventFetchData,
+        f: &mut impl for<'a> FnMut(&'a StaEvent) -> R,
+    ) -> R {
+        let event = match data.event_id as u32 {
+            wifi_event_t_WIFI_EVENT_STA_CONNECTED => StaEvent::Connected,
+            wifi_event_t_WIFI_EVENT_STA_DISCONNECTED => {
+                let disconnected =
+                    unsafe { (data.payload as *const wifi_event_sta_disconnected_t).as_ref() };
+                StaEvent::Disconnected(disconnected.map_or(0, |event| event.reason))
+            }
+            _ => StaEvent::Other,
+        };
+        f(&event)
+    }
+}
+
+pub struct Supervisor {
+    pub wifi: Wifi,
+    pub networks: Arc<Mutex<Networks>>,
+    pub status: Arc<Mutex<WifiStatus>>,
+    pub sysloop: EspSystemEventLoop,
+    pub storage: Storage,
+    pub app_storage: Storage,
+    pub on_connected: Box<dyn Fn() + Send>,
+}
+
+impl Supervisor {
+    pub fn run(mut self, events: Receiver<StaEvent>) {
+        let mut retry_at: Option<Instant> = None;
+        let mut driver_errors = 0;
+        loop {
+            // Everything queued, a flapping link can report several at once
+            let first = events.recv_timeout(super::LINK_CHECK_INTERVAL).ok();
+            for event in first.into_iter().chain(events.try_iter()) {
+                if let StaEvent::Disconnected(reason) = event {
+                    self.disconnected(reason);
+                }
+            }
+
+            if self.wifi.is_up().unwrap_or(false) {
+                retry_at = None;
+                if self.status().state != WifiState::Connected {
+                    self.connected();
+                }
+                self.status.lock().unwrap().rssi = super::rssi();
+                continue;
+            }
+
+            let attempts = self.status().reconnect_attempts;
+            let Some(at) = retry_at else {
+                let delay = backoff(attempts);
+                retry_at = Some(Instant::now() + delay);
+                self.set_waiting(delay);
+                continue;
+            };
+            let now = Instant::now();
+            if now < at {
+                self.status.lock().unwrap().retry_in = Some((at - now).as_secs());
+                continue;
+            }
+            retry_at = None;
+
+            if attempts > 0 && attempts % PORTAL_AFTER_ATTEMPTS == 0 {
+                self.last_resort();
+                discard(&events);
+                if self.wifi.is_up().unwrap_or(false) {
+                    continue;
+                }
+            }
+
+            self.set_state(WifiState::Connecting);
+            let result = super::connect_best(&mut self.wifi, &self.networks);
+            discard(&events);
+            match result {
+                Ok(true) => {
+                    driver_errors = 0;
+                    continue;
+                }
+                Ok(false) => {
+                    driver_errors = 0;
+                    warn!("No saved network in range");
+                }
+                Err(err) => {
+                    driver_errors += 1;
+                    warn!("Wi-Fi reconnect failed: {}", err);
+                    if driver_errors >= MAX_DRIVER_ERRORS {
+                        warn!("Wi-Fi driver keeps failing, rebooting");
+                        reset::restart();
+                    }
+                }
+            }
+            self.status.lock().unwrap().reconnect_attempts += 1;
+        }
+    }
+
+    fn status(&self) -> WifiStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    fn set_state(&self, state: WifiState) {
+        let mut status = self.status.lock().unwrap();
+        status.state = state;
+        status.retry_in = None;
+    }
+
+    fn set_waiting(&self, delay: Duration) {
+        let mut status = self.status.lock().unwrap();
+        status.state = WifiState::Waiting;
+        status.retry_in = Some(delay.as_secs());
+    }
+
+    fn connected(&self) {
+        let ssid = match self.wifi.get_configuration() {
+            Ok(Configuration::Client(conf)) | Ok(Configuration::Mixed(conf, _)) => {
+                Some(conf.ssid.to_string())
+            }
+            _ => None,
+        };
+        info!("Wi-Fi connected to {:?}", ssid);
+        {
+            let mut status = self.status.lock().unwrap();
+            status.state = WifiState::Connected;
+            status.ssid = ssid;
+            status.reconnect_attempts = 0;
+            status.retry_in = None;
+        }
+        (self.on_connected)();
+    }
+
+    fn disconnected(&self, reason: u8) {
+        let name = reason_name(reason);
+        let mut status = self.status.lock().unwrap();
+        // The driver reports every failed attempt too, only count lost links
+        if status.state == WifiState::Connected {
+            warn!("Wi-Fi link lost: {} ({})", name, reason);
+            status.disconnects += 1;
+            status.state = WifiState::Waiting;
+        }
+        status.last_disconnect = Some(Disconnect {
+            reason,
+            name,
+            at: (unsafe { esp_timer_get_time() } / 1_000_000) as u64,
+        });
+        status.rssi = None;
+    }
+
+    /// Provisioning if nothing is saved, otherwise the portal. Both give up
+    /// after a while so the saved networks get tried again.
+    fn last_resort(&mut self) {
+        let provision = self.networks.lock().unwrap().is_empty();
+        let result = if provision {
+            self.set_state(WifiState::Provisioning);
+            let _ = self.wifi.stop();
+            super::provision(
+                &mut self.wifi,
+                &self.sysloop,
+                &self.storage,
+                &self.app_storage,
+                super::PROVISIONING_TIMEOUT,
+            )
+        } else {
+            self.set_state(WifiState::Portal);
+            let _ = self.wifi.stop();
+            super::portal::portal(
+                &mut self.wifi,
+                &self.app_storage,
+                super::CONNECT_TIMEOUT,
+                Some(PORTAL_TIMEOUT),
+            )
+        };
+        match result {
+            Ok(true) => {
+                super::remember(&self.wifi, &self.networks);
+                self.status.lock().unwrap().device_name = self.storage.load(super::DEVICE_NAME_KEY);
+            }
+            Ok(false) => info!("Nothing configured, back to the saved networks"),
+            Err(err) => warn!("Wi-Fi fallback failed: {}", err),
+        }
+    }
+}
+
+/// Drops the events of our own connection attempts, their failures aren't
+/// lost links and the last real disconnect should stay visible.
+fn discard(events: &Receiver<StaEvent>) {
+    while events.try_recv().is_ok() {}
+}
+
+fn backoff(attempts: u32) -> Duration {
+    RECONNECT_BASE
+        .checked_mul(1 << attempts.min(16))
+        .map_or(RECONNECT_MAX, |delay| delay.min(RECONNECT_MAX))
+}
+
+fn reason_name(reason: u8) -> &'static str {
+    match reason as u32 {
+        wifi_err_reason_t_WIFI_REASON_AUTH_EXPIRE => "auth_expire",
+        wifi_err_reason_t_WIFI_REASON_AUTH_LEAVE => "auth_leave",
+        wifi_err_reason_t_WIFI_REASON_ASSOC_LEAVE => "assoc_leave",
+        wifi_err_reason_t_WIFI_REASON_4WAY_HANDSHAKE_TIMEOUT => "4way_handshake_timeout",
+        wifi_err_reason_t_WIFI_REASON_BEACON_TIMEOUT => "beacon_timeout",
+        wifi_err_reason_t_WIFI_REASON_NO_AP_FOUND => "no_ap_found",
+        wifi_err_reason_t_WIFI_REASON_AUTH_FAIL => "auth_fail",
+        wifi_err_reason_t_WIFI_REASON_ASSOC_FAIL => "assoc_fail",
+        wifi_err_reason_t_WIFI_REASON_HANDSHAKE_TIMEOUT => "handshake_timeout",
+        wifi_err_reason_t_WIFI_REASON_CONNECTION_FAIL => "connection_fail",
+        _ => "other",
+    }
+}
@@ -0,0 +1,68 @@
+//! Host side companion of the firmware provisioning, run once per device
+//! at manufacturing.
+//!
+//! ```text
+//! label <factory.csv>
+//! ```
+//!
+//! Generates the ESP-Touch v2 key and the BLE proof of possession, prints
+//! them for the label and writes them as an NVS CSV for the `factory`
+//! partition (see `partitions.csv`). The firmware never logs them, this is
+//! the only place they show up. Then:
+//!
+//! ```text
+//! nvs_partition_gen.py generate factory.csv factory.bin 0x3000
+//! esptool.py write_flash 0x3f0000 factory.bin
+//! ```
+//!
+//! The settings wipe on GPIO0 only erases the `nvs` partition, so the
+//! label stays valid.
+
+use anyhow::{bail, Context, Result};
+use rand_core::{OsRng, RngCore};
+use std::{env, fs};
+
+/// Must match `NAMESPACE` in the firmware's `wifi.rs`.
+const NAMESPACE: &str = "wifi";
+/// The AES-128 key length the ESP-Touch app expects.
+const KEY_LEN: usize = 16;
+const POP_LEN: usize = 12;
+/// Letters and digits, about 95 bits over the whole key.
+const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let [csv] = args.as_slice() else {
+        bail!("Usage:\n  label <factory.csv>");
+    };
+
+    let key = random_text(KEY_LEN);
+    let pop = random_text(POP_LEN);
+    let contents = format!(
+        "key,type,encoding,value\n{},namespace,,\ntouch_key,data,string,{}\npop,data,string,{}\n",
+        NAMESPACE, key, pop
+    );
+    fs::write(csv, contents).with_context(|| format!("Writing {}", csv))?;
+
+    println!("Clave ESP-Touch: {}", key);
+    println!("Clave BLE: {}", pop);
+    Ok(())
+}
+
+fn random_text(len: usize) -> String {
+    // Bytes past the last whole alphabet would favour its first letters
+    let whole = 256 - 256 % ALPHABET.len();
+    let mut text = String::with_capacity(len);
+    while text.len() < len {
+        let mut random = [0_u8; 32];
+        OsRng.fill_bytes(&mut random);
+        text.extend(
+            random
+                .iter()
+                .filter(|&&byte| (byte as usize) < whole)
+                .map(|&byte| ALPHABET[byte as usize % ALPHABET.len()] as char)
+                .take(len - text.len()),
+        );
+    }
+    text
+}
@@ -0,0 +1,119 @@
+//! Host side companion of the firmware OTA check.
+//!
+//! ```text
+//! ota_sign keygen <secret key> <public key>
+//! ota_sign sign <secret key> <channel> <image.bin> <version> <link> [update.json]
+//! ```
+//!
+//! The public key file is what the firmware embeds (`ota_key.pub` in the
+//! repo root). The secret key must never be committed.
+//!
+//! `sign` replaces the entry of `<channel>` (`stable` or `beta`) in the
+//! manifest and keeps the other one.
+
+use anyhow::{bail, Context, Result};
+use ed25519_dalek::{Signer, SigningKey};
+use rand_core::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{collections::BTreeMap, env, fs, path::Path};
+
+#[derive(Serialize, Deserialize, Debug)]
+struct UpdateJson {
+    version: String,
+    link: String,
+    sha256: String,
+    size: usize,
+    signature: String,
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["keygen", secret, public] => keygen(secret, public),
+        ["sign", secret, channel, image, version, link] => {
+            sign(secret, channel, image, version, link, "update.json")
+        }
+        ["sign", secret, channel, image, version, link, manifest] => {
+            sign(secret, channel, image, version, link, manifest)
+        }
+        _ => bail!(
+            "Usage:\n  ota_sign keygen <secret key> <public key>\n  ota_sign sign <secret key> <channel> <image.bin> <version> <link> [update.json]"
+        ),
+    }
+}
+
+fn keygen(secret: &str, public: &str) -> Result<()> {
+    let key = SigningKey::generate(&mut OsRng);
+    fs::write(secret, to_hex(&key.to_bytes()) + "\n")
+        .with_context(|| format!("Writing {}", secret))?;
+    fs::write(public, to_hex(key.verifying_key().as_bytes()) + "\n")
+        .with_context(|| format!("Writing {}", public))?;
+    println!("Public key: {}", to_hex(key.verifying_key().as_bytes()));
+    Ok(())
+}
+
+fn sign(
+    secret: &str,
+    channel: &str,
+    image: &str,
+    version: &str,
+    link: &str,
+    manifest: &str,
+) -> Result<()> {
+    if !["stable", "beta"].contains(&channel) {
+        bail!("Unknown channel {}, expected stable or beta", channel);
+    }
+    let secret = fs::read_to_string(secret).with_context(|| format!("Reading {}", secret))?;
+    let key = SigningKey::from_bytes(&from_hex(secret.trim())?);
+    let image = fs::read(image).with_context(|| format!("Reading {}", image))?;
+
+    let sha256 = to_hex(&Sha256::digest(&image));
+    let size = image.len();
+    let message = signed_message(channel, version, link, size, &sha256);
+    let signature = to_hex(&key.sign(message.as_bytes()).to_bytes());
+
+    let update = UpdateJson {
+        version: version.to_string(),
+        link: link.to_string(),
+        sha256,
+        size,
+        signature,
+    };
+    let mut entries: BTreeMap<String, UpdateJson> = if Path::new(manifest).exists() {
+        let current =
+            fs::read_to_string(manifest).with_context(|| format!("Reading {}", manifest))?;
+        serde_json::from_str(&current).with_context(|| format!("Parsing {}", manifest))?
+    } else {
+        BTreeMap::new()
+    };
+    entries.insert(channel.to_string(), update);
+    fs::write(manifest, serde_json::to_string_pretty(&entries)? + "\n")
+        .with_context(|| format!("Writing {}", manifest))?;
+    println!(
+        "Signed {} bytes as {} version {} into {}",
+        size, channel, version, manifest
+    );
+    Ok(())
+}
+
+/// Must match `signed_message` in the firmware.
+fn signed_message(channel: &str, version: &str, link: &str, size: usize, sha256: &str) -> String {
+    format!("{}\n{}\n{}\n{}\n{}\n", channel, version, link, size, sha256)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex<const N: usize>(hex: &str) -> Result<[u8; N]> {
+    if hex.len() != N * 2 || !hex.is_ascii() {
+        bail!("Expected {} hex digits", N * 2);
+    }
+    let mut bytes = [0_u8; N];
+    for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair)?, 16)?;
+    }
+    Ok(bytes)
+}